@@ -0,0 +1,126 @@
+use std::{
+    fs::{self, File, TryLockError}, path::{Path, PathBuf}, process, thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH}
+};
+
+use anyhow::Result;
+use canvas::{document, CanvasImage};
+
+const PREFIX: &str = "recovery-";
+const LOCK_EXTENSION: &str = "lock";
+
+/// Periodically writes the drawing to a recovery folder, so a crash loses at most one interval of work.
+/// The file of a session is removed when the app closes normally, any file left behind comes from a crash.
+/// Each session holds an exclusive lock on a file next to its autosave, so the autosaves of the other running
+/// windows aren't mistaken for crash leftovers
+pub struct Autosave {
+    dir: PathBuf,
+    path: PathBuf,
+    /// Released by the OS when the process ends, even on a crash
+    lock: Option<File>,
+    interval: Duration,
+    last_save: Instant,
+    /// Number of recorded operations at the last autosave, None if the drawing changed in another way
+    saved_operations: Option<usize>,
+    writing: Option<JoinHandle<Result<()>>>,
+}
+
+impl Autosave {
+    pub fn new(dir: PathBuf, interval: Duration) -> Self {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let path = dir.join(format!("{PREFIX}{started}-{}.{}", process::id(), document::EXTENSION));
+        let lock = fs::create_dir_all(&dir)
+            .and_then(|_| File::create(path.with_extension(LOCK_EXTENSION)))
+            .and_then(|file| file.try_lock().map(|_| file).map_err(|err| err.into()))
+            .inspect_err(|err| println!("Couldn't lock the autosave, reason: {:?}", err))
+            .ok();
+        Self { dir, path, lock, interval, last_save: Instant::now(), saved_operations: Some(0), writing: None }
+    }
+
+    /// The drawings left behind by previous sessions, most recent first
+    pub fn recovery_files(&self) -> Vec<PathBuf> {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+        let mut files = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| *path != self.path && is_recovery_file(path) && !is_in_use(path))
+            .filter_map(|path| Some((fs::metadata(&path).ok()?.modified().ok()?, path)))
+            .collect::<Vec<_>>();
+        files.sort_by(|(a, _), (b, _)| b.cmp(a));
+        files.into_iter().map(|(_, path)| path).collect()
+    }
+
+    /// To be called when the drawing is replaced, so the next tick saves it
+    pub fn mark_dirty(&mut self) {
+        self.saved_operations = None;
+    }
+
+    /// Starts writing the drawing in the background if it changed and the interval elapsed,
+    /// returns how long until the next autosave is due if there are changes left to save
+    pub fn tick(&mut self, image: &CanvasImage) -> Option<Duration> {
+        let operations = image.operation_log().map(|log| log.operations.len());
+        if operations.is_some() && operations == self.saved_operations {
+            return None;
+        }
+        let remaining = self.interval.saturating_sub(self.last_save.elapsed());
+        if !remaining.is_zero() {
+            return Some(remaining);
+        }
+        if self.writing.as_ref().is_some_and(|writing| !writing.is_finished()) {
+            return Some(Duration::from_secs(1));
+        }
+        self.finish_writing();
+        let mut bytes = Vec::new();
+        if let Err(err) = document::write_to(image, &mut bytes) {
+            println!("Couldn't autosave, reason: {:?}", err);
+            self.last_save = Instant::now();
+            return Some(self.interval);
+        }
+        let (dir, path) = (self.dir.clone(), self.path.clone());
+        self.writing = Some(thread::spawn(move || {
+            fs::create_dir_all(dir)?;
+            // the previous autosave stays valid until the new one is complete
+            let partial = path.with_extension("partial");
+            fs::write(&partial, bytes)?;
+            fs::rename(partial, path)?;
+            Ok(())
+        }));
+        self.last_save = Instant::now();
+        self.saved_operations = operations;
+        None
+    }
+
+    fn finish_writing(&mut self) {
+        if let Some(Ok(Err(err))) = self.writing.take().map(JoinHandle::join) {
+            println!("Couldn't autosave, reason: {:?}", err);
+        }
+    }
+
+    /// Removes the recovery file of this session, once the drawing doesn't need to be recovered anymore
+    pub fn discard(&mut self) {
+        self.finish_writing();
+        remove_recovery_file(&self.path);
+        self.lock = None;
+    }
+}
+
+/// Removes a recovery file along with its lock file
+pub fn remove_recovery_file(path: &Path) {
+    let _ = fs::remove_file(path);
+    let _ = fs::remove_file(path.with_extension(LOCK_EXTENSION));
+}
+
+/// Whether the session which wrote a recovery file is still running, files from before the lock files count as left behind
+fn is_in_use(path: &Path) -> bool {
+    let Ok(file) = File::open(path.with_extension(LOCK_EXTENSION)) else {
+        return false;
+    };
+    matches!(file.try_lock(), Err(TryLockError::WouldBlock))
+}
+
+fn is_recovery_file(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == document::EXTENSION)
+        && path.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.starts_with(PREFIX))
+}
@@ -0,0 +1,166 @@
+use std::{path::Path, process::ExitCode};
+
+use anyhow::{bail, Context, Result};
+use canvas::{
+    document, export::{self, ExportOptions}, timelapse::{self, TimelapseOptions}, CanvasImage, Dithering, OperationLog
+};
+use image::{imageops::{self, FilterType}, Rgba};
+
+const USAGE: &str = "Batch processing of canvas documents (.canvas) and images
+
+Usage:
+    canvas-cli convert <input> <output>
+    canvas-cli render <input> <output> [--scale <factor>] [--smooth] [--quality <1-100>] [--background <color>]
+                      [--indexed <none|ordered|floyd-steinberg>] [--embed]
+    canvas-cli palette <input>
+    canvas-cli swap <input> <output> <from color> <to color>
+    canvas-cli resize <input> <output> <width> <height>
+    canvas-cli crop <input> <output> <x> <y> <width> <height>
+    canvas-cli replay <operation log> <output>
+    canvas-cli timelapse <operation log> <output> [--interval <operations>] [--fps <rate>] [--scale <factor>]
+
+The format of the files is picked from their extension (canvas, png, jpg, webp, bmp, tiff, qoi),
+colors are written as #rrggbb or #rrggbbaa.
+PNGs embed the document (except with render unless --embed), which is preferred when opening them";
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<()> {
+    let Some(command) = args.first() else {
+        println!("{USAGE}");
+        return Ok(());
+    };
+    let args = &args[1..];
+    match (command.as_str(), args) {
+        ("convert", [input, output]) => {
+            document::save(&open(input)?, Path::new(output))
+        },
+        ("render", [input, output, options @ ..]) => {
+            render(&open(input)?, Path::new(output), options)
+        },
+        ("palette", [input]) => {
+            let image = open(input)?;
+            let pixels = (image.width()*image.height()) as f32;
+            for (color, coverage) in image.coverage() {
+                println!("{}\t{:.2}%", format_color(color), coverage/pixels*100.);
+            }
+            Ok(())
+        },
+        ("swap", [input, output, from, to]) => {
+            let mut image = open(input)?;
+            let from = parse_color(from)?;
+            if !image.swap_color(from, parse_color(to)?) {
+                bail!("{} is not in the palette", format_color(from));
+            }
+            document::save(&image, Path::new(output))
+        },
+        ("resize", [input, output, width, height]) => {
+            let image = open(input)?.resized(parse(width)?, parse(height)?);
+            document::save(&image, Path::new(output))
+        },
+        ("crop", [input, output, x, y, width, height]) => {
+            let image = open(input)?.cropped([parse(x)?, parse(y)?], parse(width)?, parse(height)?);
+            document::save(&image, Path::new(output))
+        },
+        ("replay", [log, output]) => {
+            let image = OperationLog::open(Path::new(log))?.replay()?;
+            document::save(&image, Path::new(output))
+        },
+        ("timelapse", [log, output, options @ ..]) => {
+            export_timelapse(&OperationLog::open(Path::new(log))?, Path::new(output), options)
+        },
+        ("help" | "--help" | "-h", _) => {
+            println!("{USAGE}");
+            Ok(())
+        },
+        _ => bail!("invalid arguments\n\n{USAGE}"),
+    }
+}
+
+fn open(path: &str) -> Result<CanvasImage> {
+    document::open(Path::new(path))
+}
+
+fn render(image: &CanvasImage, output: &Path, options: &[String]) -> Result<()> {
+    let mut scale = 1.;
+    let mut filter = FilterType::Nearest;
+    let mut export_options = ExportOptions::default();
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let mut value = || options.next().with_context(|| format!("missing value for {option}"));
+        match option.as_str() {
+            "--scale" => scale = parse(value()?)?,
+            "--smooth" => filter = FilterType::Triangle,
+            "--quality" => export_options.quality = parse(value()?)?,
+            "--indexed" => {
+                export_options.indexed = true;
+                export_options.dithering = match value()?.as_str() {
+                    "none" => Dithering::None,
+                    "ordered" => Dithering::Ordered,
+                    "floyd-steinberg" => Dithering::FloydSteinberg,
+                    dithering => bail!("unknown dithering {dithering}, expected none, ordered or floyd-steinberg"),
+                };
+            },
+            "--embed" => export_options.embed_document = true,
+            "--background" => {
+                export_options.background = parse_color(value()?)?;
+                export_options.flatten = true;
+            },
+            _ => bail!("unknown option {option}"),
+        }
+    }
+    if scale <= 0. {
+        bail!("the scale must be positive");
+    }
+    let render = image.render();
+    let width = ((render.width() as f32*scale).round() as u32).max(1);
+    let height = ((render.height() as f32*scale).round() as u32).max(1);
+    let resized = (scale != 1.).then(|| image.resized(width as usize, height as usize));
+    let source = resized.as_ref().unwrap_or(image);
+    export::save_render(&imageops::resize(&render, width, height, filter), Some(source), output, &export_options)
+}
+
+fn export_timelapse(log: &OperationLog, output: &Path, options: &[String]) -> Result<()> {
+    let mut timelapse_options = TimelapseOptions::default();
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let mut value = || options.next().with_context(|| format!("missing value for {option}"));
+        match option.as_str() {
+            "--interval" => timelapse_options.interval = parse(value()?)?,
+            "--fps" => timelapse_options.frame_rate = parse(value()?)?,
+            "--scale" => timelapse_options.scale = parse(value()?)?,
+            _ => bail!("unknown option {option}"),
+        }
+    }
+    timelapse::export(log, &timelapse_options, output)
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> Result<T> {
+    value.parse().ok().with_context(|| format!("invalid number {value}"))
+}
+
+fn parse_color(value: &str) -> Result<Rgba<u8>> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if !matches!(hex.len(), 6 | 8) || !hex.is_ascii() {
+        bail!("invalid color {value}, expected #rrggbb or #rrggbbaa");
+    }
+    let mut rgba = [u8::MAX; 4];
+    for (i, channel) in rgba.iter_mut().take(hex.len()/2).enumerate() {
+        *channel = u8::from_str_radix(&hex[i*2..i*2+2], 16)
+            .with_context(|| format!("invalid color {value}"))?;
+    }
+    Ok(Rgba(rgba))
+}
+
+fn format_color(color: Rgba<u8>) -> String {
+    format!("#{:02x}{:02x}{:02x}{:02x}", color[0], color[1], color[2], color[3])
+}
@@ -1,8 +1,14 @@
+use glam::Vec2;
 use grid::Grid;
+use serde::{Deserialize, Serialize};
 
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Brush {
     pub texture: Grid<u8>,
     pub spacing: f32,
+    /// Stamps are placed at fractional positions by resampling the texture,
+    /// disable for hard pixel aligned stamps (pixel art)
+    pub subpixel: bool,
 }
 
 impl Brush {
@@ -13,6 +19,23 @@ impl Brush {
     pub fn height(&self) -> usize {
         self.texture.rows()
     }
+
+    /// Resamples the texture shifted by a sub-pixel offset (each component in [0, 1[) using bilinear weights,
+    /// the result is 1 pixel wider and taller than the texture
+    pub fn shifted_texture(&self, offset: Vec2) -> Grid<u8> {
+        let (rows, cols) = self.texture.size();
+        let mut res = Grid::new(rows+1, cols+1);
+        let sample = |x: usize, y: usize| *self.texture.get(x, y).unwrap_or(&0) as f32;
+        for ((x, y), pixel) in res.indexed_iter_mut() {
+            // each destination pixel gets a mix of the 4 texture pixels overlapping it
+            let left = x.checked_sub(1).map_or(0., |x_1| {
+                sample(x_1, y)*(1.-offset.y) + y.checked_sub(1).map_or(0., |y_1| sample(x_1, y_1)*offset.y)
+            });
+            let right = sample(x, y)*(1.-offset.y) + y.checked_sub(1).map_or(0., |y_1| sample(x, y_1)*offset.y);
+            *pixel = (left*offset.x + right*(1.-offset.x)).round().min(u8::MAX as f32) as u8;
+        }
+        res
+    }
 }
 
 pub fn round_brush(diameter: usize) -> Brush {
@@ -27,5 +50,52 @@ pub fn round_brush(diameter: usize) -> Brush {
             *pixel = (dist_to_edge*(u8::MAX-1) as f32) as u8;
         }
     }
-    Brush { texture: grid, spacing: 1. }
-}
\ No newline at end of file
+    Brush { texture: grid, spacing: 1., subpixel: true }
+}
+
+/// A hard disc without anti-aliasing, stamped on whole pixels, for pixel art
+pub fn pixel_brush(diameter: usize) -> Brush {
+    let mut grid = Grid::new(diameter, diameter);
+    let r = diameter as f32/2.;
+    for ((x, y), pixel) in grid.indexed_iter_mut() {
+        // the pixel centers inside the disc
+        let dist_to_center = ((x as f32+0.5-r).powi(2) + (y as f32+0.5-r).powi(2)).sqrt();
+        if dist_to_center <= r.max(0.5) {
+            *pixel = u8::MAX;
+        }
+    }
+    Brush { texture: grid, spacing: 1., subpixel: false }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec2;
+    use super::{pixel_brush, round_brush};
+
+    #[test]
+    pub fn test_pixel_brush() {
+        assert_eq!(pixel_brush(1).texture.flatten(), &[u8::MAX]);
+        let brush = pixel_brush(4);
+        // the corners are cut, everything else is fully covered
+        for ((x, y), &val) in brush.texture.indexed_iter() {
+            let corner = (x == 0 || x == 3) && (y == 0 || y == 3);
+            assert_eq!(val, if corner { 0 } else { u8::MAX });
+        }
+    }
+
+    #[test]
+    pub fn test_shifted_texture() {
+        let brush = round_brush(5);
+        let unshifted = brush.shifted_texture(Vec2::ZERO);
+        for ((x, y), &val) in brush.texture.indexed_iter() {
+            assert_eq!(val, unshifted[(x, y)]);
+        }
+        // half a pixel to the right, every pixel is the average of its 2 horizontal sources
+        let shifted = brush.shifted_texture(Vec2::new(0.5, 0.));
+        for ((x, y), &val) in shifted.indexed_iter() {
+            let left = if x > 0 { *brush.texture.get(x-1, y).unwrap_or(&0) as f32 } else { 0. };
+            let right = *brush.texture.get(x, y).unwrap_or(&0) as f32;
+            assert!((val as f32 - (left+right)/2.).abs() <= 0.5);
+        }
+    }
+}
@@ -1,34 +1,86 @@
-use eframe::egui::Pos2;
+use glam::{IVec2, Vec2};
 
 use crate::array_queue::ArrayQueue;
 
 pub struct BrushStroke {
     // can store as many past positions as we need 
     // depending on the stroke correction algorithm
-    pos_buffer: ArrayQueue<Pos2, 3>,
+    pos_buffer: ArrayQueue<Vec2, 3>,
+    /// Keeps 1 pixel lines free of L-shaped corners, for 1 pixel pencils
+    pub pixel_perfect: bool,
+    /// The last pixel of a pixel perfect stroke which was handed out
+    last_pixel: Option<IVec2>,
+    /// The pixel after it, held back until the next one tells whether it's a corner
+    pending_pixel: Option<IVec2>,
+    /// Set when the stroke went back over the last pixel, which is pending again but already handed out
+    pending_stamped: bool,
+}
+
+impl Default for BrushStroke {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BrushStroke {
     pub fn new() -> Self {
-        Self { pos_buffer: ArrayQueue::new() }
+        Self { pos_buffer: ArrayQueue::new(), pixel_perfect: false, last_pixel: None, pending_pixel: None, pending_stamped: false }
     }
     /// Takes a new brush position as well as a brush spacing value, 
     /// and outputs all the position the brush needs to be applied to
-    pub fn update_stroke(&mut self, new_pos: Pos2, spacing: f32) -> Vec<Pos2> {
+    pub fn update_stroke(&mut self, new_pos: Vec2, spacing: f32) -> Vec<Vec2> {
         self.pos_buffer.push(new_pos);
-        if self.pos_buffer.len() == 1 {
-            return vec![new_pos];
+        let poses = if self.pos_buffer.len() == 1 {
+            vec![new_pos]
+        } else {
+            // for now we just trace a line between the new pos and the previous pos
+            spaced_lerp(self.pos_buffer[1], self.pos_buffer[0], spacing)
+        };
+        if !self.pixel_perfect {
+            return poses;
+        }
+        // the lerp goes from the new pos back to the previous one
+        poses.into_iter().rev().filter_map(|pos| self.push_pixel(pos.floor().as_ivec2())).collect()
+    }
+
+    /// The pixel centers to stamp once the pixel before `pixel` is known not to be a corner
+    fn push_pixel(&mut self, pixel: IVec2) -> Option<Vec2> {
+        if self.pending_pixel.or(self.last_pixel) == Some(pixel) {
+            return None;
         }
-        // for now we just trace a line between the new pos and the previous pos
-        spaced_lerp(self.pos_buffer[1], self.pos_buffer[0], spacing)
+        let is_corner = self.last_pixel.is_some_and(|last| (pixel - last).abs() == IVec2::ONE);
+        let doubles_back = self.last_pixel == Some(pixel);
+        let res = match self.pending_pixel {
+            // the pending pixel only joins 2 diagonal pixels, it's dropped
+            Some(_) if is_corner => None,
+            Some(pending) => {
+                self.last_pixel = Some(pending);
+                (!self.pending_stamped).then(|| pending.as_vec2() + 0.5)
+            },
+            None => None,
+        };
+        self.pending_pixel = Some(pixel);
+        self.pending_stamped = doubles_back;
+        res
+    }
+
+    /// Ends the stroke, returning the stamps held back by the pixel perfect mode
+    pub fn finish_stroke(&mut self) -> Vec<Vec2> {
+        let res = self.pending_pixel.filter(|_| !self.pending_stamped)
+            .map(|pixel| pixel.as_vec2() + 0.5).into_iter().collect();
+        self.clear_stroke();
+        res
     }
 
     pub fn clear_stroke(&mut self) {
         self.pos_buffer.clear();
+        self.last_pixel = None;
+        self.pending_pixel = None;
+        self.pending_stamped = false;
     }
 }
 
-fn spaced_lerp(start: Pos2, end: Pos2, spacing: f32) -> Vec<Pos2> {
+pub fn spaced_lerp(start: Vec2, end: Vec2, spacing: f32) -> Vec<Vec2> {
     let diff = start-end;
     let dist = diff.length();
     let step = diff/dist;
@@ -39,4 +91,56 @@ fn spaced_lerp(start: Pos2, end: Pos2, spacing: f32) -> Vec<Pos2> {
         i += spacing;
     }
     res
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use glam::Vec2;
+    use super::BrushStroke;
+
+    /// Ascii art of the stamped pixels
+    fn stamped(poses: &[Vec2], width: usize, height: usize) -> Vec<String> {
+        (0..height).map(|y| (0..width).map(|x| {
+            let center = Vec2::new(x as f32 + 0.5, y as f32 + 0.5);
+            if poses.contains(&center) { '#' } else { '.' }
+        }).collect()).collect()
+    }
+
+    fn unique_count(poses: &[Vec2]) -> usize {
+        poses.iter().map(|pos| pos.to_array().map(f32::to_bits)).collect::<HashSet<_>>().len()
+    }
+
+    #[test]
+    pub fn test_pixel_perfect() {
+        let mut stroke = BrushStroke::new();
+        stroke.pixel_perfect = true;
+        let mut poses = Vec::new();
+        // a staircase, the lerp goes through the corners
+        for pos in [(0.5, 0.5), (1.5, 0.5), (1.5, 1.5), (2.5, 1.5), (2.5, 2.5), (4.5, 2.5)] {
+            poses.extend(stroke.update_stroke(Vec2::new(pos.0, pos.1), 1.));
+        }
+        poses.extend(stroke.finish_stroke());
+        assert_eq!(stamped(&poses, 5, 3), [
+            "#....",
+            ".#...",
+            "..###",
+        ]);
+        // every pixel is stamped once
+        assert_eq!(unique_count(&poses), poses.len());
+    }
+
+    #[test]
+    pub fn test_pixel_perfect_doubling_back() {
+        let mut stroke = BrushStroke::new();
+        stroke.pixel_perfect = true;
+        let mut poses = Vec::new();
+        // a wiggle going back over the last handed out pixel
+        for pos in [(0.5, 0.5), (1.5, 0.5), (2.5, 0.5), (1.5, 0.5), (2.5, 0.5), (4.5, 0.5)] {
+            poses.extend(stroke.update_stroke(Vec2::new(pos.0, pos.1), 1.));
+        }
+        poses.extend(stroke.finish_stroke());
+        assert_eq!(stamped(&poses, 5, 1), ["#####"]);
+        assert_eq!(unique_count(&poses), poses.len());
+    }
+}
@@ -1,24 +1,147 @@
 use std::borrow::Cow;
 use std::path::PathBuf;
-use arboard::Clipboard;
-use arboard::ImageData;
+use std::time::Duration;
 use eframe::egui;
 use eframe::egui::*;
 use eframe::App;
-use image::ExtendedColorType;
+use image::imageops;
+use image::imageops::FilterType;
+use image::RgbaImage;
 
-use crate::brush::round_brush;
-use crate::brush::Brush;
-use crate::brush_stroke::BrushStroke;
-use crate::canvas_image::CanvasImage;
+use crate::autosave;
+use crate::autosave::Autosave;
+use crate::clipboard::CanvasClipboard;
+use crate::clipboard::ClipboardContent;
+use crate::keymap;
+use crate::keymap::Action;
+use crate::keymap::Keymap;
 
-#[derive(PartialEq, Eq)]
+use canvas::pixel_brush;
+use canvas::round_brush;
+use canvas::document;
+use canvas::export;
+use canvas::export::ExportFormat;
+use canvas::export::ExportOptions;
+use canvas::Dithering;
+use canvas::Brush;
+use canvas::BrushStroke;
+use canvas::CanvasImage;
+use canvas::FillOptions;
+use canvas::FillSample;
+use canvas::Gradient;
+use canvas::GradientKind;
+use canvas::OperationLog;
+use canvas::Shape;
+use canvas::ShapeKind;
+use canvas::Symmetry;
+use canvas::SymmetryKind;
+use canvas::timelapse;
+use canvas::timelapse::TimelapseOptions;
+
+const DEFAULT_SIZE: [usize; 2] = [640, 480];
+const RECENT_FILES_KEY: &str = "recent_files";
+const MAX_RECENT_FILES: usize = 10;
+/// Zoom factor of one zoom in or out step
+const ZOOM_STEP: f32 = 1.25;
+/// Rotation of one rotate left or right step, in degrees
+const ROTATION_STEP: f32 = 15.;
+/// The pixel grid only shows from this zoom on, in screen points per canvas pixel
+const PIXEL_GRID_MIN_ZOOM: f32 = 6.;
+/// Screen points per canvas pixel, zoomed all the way in a pixel art canvas shows a few dozen pixels
+const ZOOM_RANGE: Rangef = Rangef { min: 0.1, max: 64. };
+/// Side of the scaling handle of floating pastes, in screen points
+const HANDLE_SIZE: f32 = 8.;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
 enum Tool {
     Brush,
+    Eraser,
     Fill,
+    Shape(ShapeKind),
+    Gradient,
     Selection,
 }
 
+/// Actions replacing the drawing, they need a confirmation when it has unsaved changes
+enum DiscardingAction {
+    New,
+    /// Opens the file, or asks for one if None
+    Open(Option<PathBuf>),
+    /// Replaces the drawing by the clipboard image
+    PasteAsNew,
+    Quit,
+}
+
+/// A pasted image which isn't part of the drawing yet, it can be moved and scaled until it is committed
+struct FloatingPaste {
+    image: RgbaImage,
+    /// The presences of the paste when it was copied from a drawing, committed instead of the image
+    document: Option<CanvasImage>,
+    texture: TextureHandle,
+    /// Top left corner, in canvas pixels
+    pos: glam::Vec2,
+    scale: f32,
+    /// Offset from the top left corner to the pointer while the paste is dragged
+    grab: Option<glam::Vec2>,
+}
+
+impl FloatingPaste {
+    fn rect(&self) -> Rect {
+        let size = vec2(self.image.width() as f32, self.image.height() as f32)*self.scale;
+        Rect::from_min_size(pos2(self.pos.x, self.pos.y), size)
+    }
+}
+
+/// Rotation and mirroring of the view around the center of the canvas, the pixels are left untouched.
+/// The scene only zooms and pans, the canvas is painted through this transform inside it
+#[derive(Clone, Copy)]
+struct ViewTransform {
+    /// Clockwise, in radians
+    rotation: f32,
+    /// Mirrored left to right, before the rotation
+    flipped: bool,
+    center: Pos2,
+}
+
+impl ViewTransform {
+    fn to_scene(self, canvas_pos: Pos2) -> Pos2 {
+        let mut offset = canvas_pos - self.center;
+        if self.flipped {
+            offset.x = -offset.x;
+        }
+        self.center + emath::Rot2::from_angle(self.rotation)*offset
+    }
+
+    fn to_canvas(self, scene_pos: Pos2) -> Pos2 {
+        let mut offset = emath::Rot2::from_angle(-self.rotation)*(scene_pos - self.center);
+        if self.flipped {
+            offset.x = -offset.x;
+        }
+        self.center + offset
+    }
+
+    /// The corners of a canvas rect in the scene, clockwise from the top left one of the canvas
+    fn quad(&self, rect: Rect) -> [Pos2; 4] {
+        [rect.left_top(), rect.right_top(), rect.right_bottom(), rect.left_bottom()].map(|pos| self.to_scene(pos))
+    }
+
+    fn bounding_rect(&self, rect: Rect) -> Rect {
+        Rect::from_points(&self.quad(rect))
+    }
+
+    /// Paints a texture covering a canvas rect
+    fn paint_texture(&self, ui: &Ui, texture: TextureId, rect: Rect) {
+        let mut mesh = epaint::Mesh::with_texture(texture);
+        let uvs = [pos2(0., 0.), pos2(1., 0.), pos2(1., 1.), pos2(0., 1.)];
+        for (pos, uv) in self.quad(rect).into_iter().zip(uvs) {
+            mesh.vertices.push(epaint::Vertex { pos, uv, color: Color32::WHITE });
+        }
+        mesh.add_triangle(0, 1, 2);
+        mesh.add_triangle(0, 2, 3);
+        ui.painter().add(mesh);
+    }
+}
+
 pub struct CanvasApp {
     image: CanvasImage,
     render_texture: TextureHandle,
@@ -26,21 +149,80 @@ pub struct CanvasApp {
     brush: Brush,
     brush_stroke: BrushStroke,
     stroke_width: u32,
+    /// Sub-pixel stamping outside of the pixel art mode
+    subpixel: bool,
+    /// Hard pixel aligned brushes
+    pixel_art: bool,
+    /// Removes the corners of 1 pixel lines in pixel art mode
+    pixel_perfect: bool,
+    pixel_grid: bool,
+    /// Repeats the brush and eraser stamps
+    symmetry: Symmetry,
     stroke_color: Color32,
+    /// Swapped with the stroke color by the swap colors shortcut
+    secondary_color: Color32,
+    fill_options: FillOptions,
+    shape_filled: bool,
+    /// The shape being drawn, if any
+    shape: Option<Shape>,
+    gradient: Gradient,
+    /// Start and end of the gradient being dragged, if any
+    gradient_line: Option<(glam::Vec2, glam::Vec2)>,
+    timelapse_options: TimelapseOptions,
+    export_format: ExportFormat,
+    export_options: ExportOptions,
+    /// Whether the export dialog is shown, with the error of the last attempt if it failed
+    export_dialog: Option<Option<String>>,
     dragging: bool,
     saving_path: Option<PathBuf>,
     unsaved_changes: bool,
     last_title: String,
-    clipboard: Clipboard,
+    recent_files: Vec<PathBuf>,
+    /// Waiting for the user to decide what to do with the unsaved changes
+    pending_action: Option<DiscardingAction>,
+    /// Set once the user agreed to close the app
+    closing: bool,
+    floating: Option<FloatingPaste>,
+    /// Position of the pointer over the canvas, in canvas pixels
+    hover_pos: Option<glam::Vec2>,
+    clipboard: Option<CanvasClipboard>,
+    /// The part of the canvas in view, the scene letterboxes it in the view
     camera: Rect,
+    /// Where the scene was shown last frame, in screen points
+    view_rect: Rect,
+    /// How the scene showed the camera last frame, from the scene to screen points
+    scene_transform: emath::TSTransform,
+    /// Position of the pointer in the view, in canvas pixels
+    view_pointer: Option<Pos2>,
+    /// Set while the view is dragged with the middle button or with space held
+    panning: bool,
+    view: ViewTransform,
+    keymap: Keymap,
+    keymap_window: bool,
+    /// The action waiting for its new shortcut in the keymap window
+    rebinding: Option<Action>,
+    autosave: Autosave,
+    /// Drawings left behind by a crash, offered for restoration
+    recovery_files: Vec<PathBuf>,
 }
 
 impl CanvasApp {
     pub fn new(_cc: &eframe::CreationContext<'_>) -> Self {
-        let width = 640;
-        let height = 480;
+        let [width, height] = DEFAULT_SIZE;
+        let mut image = CanvasImage::new(width, height);
+        image.start_recording();
+        let storage_dir = eframe::storage_dir("Canvas").unwrap_or_else(std::env::temp_dir);
+        let recovery_dir = storage_dir.join("recovery");
+        let keymap = Keymap::load(storage_dir.join("keymap.json"));
+        // the zoom shortcuts zoom the drawing instead of the interface
+        _cc.egui_ctx.options_mut(|options| options.zoom_with_keyboard = false);
+        let clipboard = CanvasClipboard::new(storage_dir.join("clipboard"))
+            .inspect_err(|err| println!("Couldn't access the clipboard, reason: {:?}", err))
+            .ok();
+        let autosave = Autosave::new(recovery_dir, Duration::from_secs(30));
+        let recovery_files = autosave.recovery_files();
         Self {
-            image: CanvasImage::new(width, height),
+            image,
             render_texture: _cc.egui_ctx.load_texture(
                 "render",
                 ColorImage::new([width, height], Color32::TRANSPARENT),
@@ -50,136 +232,1141 @@ impl CanvasApp {
             brush_stroke: BrushStroke::new(),
             tool: Tool::Brush,
             stroke_width: 3,
+            subpixel: true,
+            pixel_art: false,
+            pixel_perfect: true,
+            pixel_grid: false,
+            symmetry: Symmetry::new(SymmetryKind::None, glam::Vec2::new(width as f32, height as f32)/2.),
             stroke_color: Color32::from_rgb(25, 200, 100),
+            secondary_color: Color32::WHITE,
+            fill_options: FillOptions::default(),
+            shape_filled: false,
+            shape: None,
+            gradient: Gradient::new(
+                GradientKind::Linear, 
+                to_rgba8(Color32::from_rgb(25, 200, 100)), 
+                to_rgba8(Color32::WHITE)
+            ),
+            gradient_line: None,
+            timelapse_options: TimelapseOptions::default(),
+            export_format: ExportFormat::Png,
+            export_options: ExportOptions::default(),
+            export_dialog: None,
             dragging: false,
             saving_path: None,
-            unsaved_changes: true,
+            unsaved_changes: false,
             last_title: String::new(),
-            clipboard: Clipboard::new().unwrap(),
+            recent_files: _cc.storage
+                .and_then(|storage| eframe::get_value(storage, RECENT_FILES_KEY))
+                .unwrap_or_default(),
+            pending_action: None,
+            closing: false,
+            floating: None,
+            hover_pos: None,
+            clipboard,
             camera: Rect::ZERO,
+            view_rect: Rect::ZERO,
+            scene_transform: emath::TSTransform::IDENTITY,
+            view_pointer: None,
+            panning: false,
+            view: ViewTransform { rotation: 0., flipped: false, center: Pos2::ZERO },
+            keymap,
+            keymap_window: false,
+            rebinding: None,
+            autosave,
+            recovery_files,
         }
     }
 
     fn title(&self) -> String {
-        let Some(path) = &self.saving_path else {
-            return "New drawing.png*".to_string();
-        };
-        format!("{}{}", path.file_name().unwrap().to_str().unwrap(), if self.unsaved_changes {
-            "*"
-        } else { "" })
+        let name = self.saving_path.as_ref()
+            .and_then(|path| path.file_name())
+            .map_or(Cow::Borrowed("New drawing"), |name| name.to_string_lossy());
+        format!("{}{}", name, if self.unsaved_changes { "*" } else { "" })
     }
 
-    fn save(&mut self) {
-        let path = match &self.saving_path {
-            Some(path) => path,
-            None => if let Some(path) = rfd::FileDialog::new().save_file() {
-                self.saving_path = Some(path);
-                self.saving_path.as_ref().unwrap()
-            } else {
-                println!("Couldn't get a path to save the image ...");
-                return;
+    /// Replaces the drawing, `path` is where it will be saved
+    fn set_image(&mut self, mut image: CanvasImage, path: Option<PathBuf>) {
+        image.start_recording();
+        self.image = image;
+        self.saving_path = path;
+        self.unsaved_changes = false;
+        self.shape = None;
+        self.gradient_line = None;
+        self.brush_stroke.clear_stroke();
+        self.dragging = false;
+        self.floating = None;
+        self.symmetry.center = glam::Vec2::new(self.image.width() as f32, self.image.height() as f32)/2.;
+        // the scene fits the new canvas
+        self.camera = Rect::ZERO;
+        self.autosave.mark_dirty();
+        self.render_texture.set(to_color_image(&self.image.render()), TextureOptions::NEAREST);
+    }
+
+    fn request(&mut self, ctx: &Context, action: DiscardingAction) {
+        if self.unsaved_changes {
+            self.pending_action = Some(action);
+        } else {
+            self.perform(ctx, action);
+        }
+    }
+
+    fn perform(&mut self, ctx: &Context, action: DiscardingAction) {
+        match action {
+            DiscardingAction::New => self.set_image(CanvasImage::new(DEFAULT_SIZE[0], DEFAULT_SIZE[1]), None),
+            DiscardingAction::Open(path) => {
+                let path = path.or_else(|| {
+                    let mut extensions = vec![document::EXTENSION];
+                    extensions.extend(ExportFormat::ALL.iter().flat_map(|format| format.extensions()));
+                    rfd::FileDialog::new().add_filter("Drawings", &extensions).pick_file()
+                });
+                if let Some(path) = path {
+                    self.open(path);
+                }
+            },
+            DiscardingAction::PasteAsNew => {
+                let Some(pasted) = self.clipboard.as_mut().and_then(CanvasClipboard::paste) else {
+                    return;
+                };
+                self.set_image(pasted.into_canvas(), None);
+                self.unsaved_changes = true;
+            },
+            DiscardingAction::Quit => {
+                self.closing = true;
+                ctx.send_viewport_cmd(ViewportCommand::Close);
+            },
+        }
+    }
+
+    fn open(&mut self, path: PathBuf) {
+        match document::open(&path) {
+            Ok(image) => {
+                self.add_recent_file(path.clone());
+                self.set_image(image, Some(path));
+            },
+            Err(err) => {
+                println!("Couldn't open {}, reason: {:?}", path.display(), err);
+                self.recent_files.retain(|recent| *recent != path);
             },
+        }
+    }
+
+    fn add_recent_file(&mut self, path: PathBuf) {
+        self.recent_files.retain(|recent| *recent != path);
+        self.recent_files.insert(0, path);
+        self.recent_files.truncate(MAX_RECENT_FILES);
+    }
+
+    /// Saves to the current path, or asks for one, returns whether the drawing was saved
+    fn save(&mut self) -> bool {
+        let Some(path) = self.saving_path.clone() else {
+            return self.save_as();
         };
-        let render = self.image.render();
-        if let Err(err) = image::save_buffer(
-            path, 
-            render.as_raw(), 
-            render.width() as u32, 
-            render.height() as u32, 
-            ExtendedColorType::Rgba8
-        ) {
+        self.save_to(path)
+    }
+
+    fn save_as(&mut self) -> bool {
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("Canvas document", &[document::EXTENSION])
+            .add_filter("PNG", &["png"])
+            .set_file_name(format!("New drawing.{}", document::EXTENSION))
+            .save_file() else {
+            return false;
+        };
+        self.save_to(path)
+    }
+
+    /// The document only points at the path once it was written there
+    fn save_to(&mut self, path: PathBuf) -> bool {
+        if let Err(err) = document::save(&self.image, &path) {
             println!("Couldn't save image to path, reason: {:?}", err);
+            return false;
+        }
+        self.saving_path = Some(path.clone());
+        self.unsaved_changes = false;
+        self.add_recent_file(path);
+        true
+    }
+
+    /// Writes a flattened copy of the drawing, without changing where it is saved
+    fn export(&mut self) -> Result<(), String> {
+        let format = self.export_format;
+        let name = self.saving_path.as_ref()
+            .and_then(|path| path.file_stem())
+            .map_or(Cow::Borrowed("New drawing"), |name| name.to_string_lossy());
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter(format.name(), format.extensions())
+            .set_file_name(format!("{}.{}", name, format.extensions()[0]))
+            .save_file() else {
+            return Ok(());
+        };
+        export::export(&self.image, &path, &self.export_options).map_err(|err| format!("{err:#}"))
+    }
+
+    fn ui_export(&mut self, ctx: &Context) {
+        let Some(error) = &self.export_dialog else {
+            return;
+        };
+        let mut error = error.clone();
+        let mut open = true;
+        let mut done = false;
+        Window::new("Export")
+            .open(&mut open)
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                let options = &mut self.export_options;
+                ComboBox::from_label("Format")
+                    .selected_text(self.export_format.name())
+                    .show_ui(ui, |ui| {
+                        for format in ExportFormat::ALL {
+                            ui.selectable_value(&mut self.export_format, format, format.name());
+                        }
+                    });
+                ui.add_enabled(
+                    self.export_format.has_quality(),
+                    Slider::new(&mut options.quality, 1..=100).text("Quality")
+                );
+                ui.add_enabled_ui(self.export_format.supports_indexed(), |ui| {
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut options.indexed, "Indexed colors")
+                            .on_hover_text("At most 256 colors, the drawing is dithered with its palette when it has more");
+                        ui.add_enabled_ui(options.indexed, |ui| {
+                            ComboBox::from_id_salt("dithering")
+                                .selected_text(options.dithering.name())
+                                .show_ui(ui, |ui| {
+                                    for dithering in Dithering::ALL {
+                                        ui.selectable_value(&mut options.dithering, dithering, dithering.name());
+                                    }
+                                });
+                        });
+                    });
+                    ui.checkbox(&mut options.embed_document, "Embed the document")
+                        .on_hover_text("Reopening the png gives back the exact color presences");
+                });
+                ui.horizontal(|ui| {
+                    let supports_alpha = self.export_format.supports_alpha();
+                    let mut flatten = options.flatten || !supports_alpha;
+                    ui.add_enabled(supports_alpha, Checkbox::new(&mut flatten, "Flatten onto"))
+                        .on_hover_text("Replace the transparency by a background color, always done for formats without alpha");
+                    if supports_alpha {
+                        options.flatten = flatten;
+                    }
+                    let mut background = to_color32(options.background);
+                    if color_picker::color_edit_button_srgba(ui, &mut background, color_picker::Alpha::Opaque).changed() {
+                        options.background = to_rgba8(background);
+                    }
+                });
+                if let Some(error) = &error {
+                    ui.colored_label(ui.visuals().error_fg_color, error);
+                }
+                if ui.button("Export...").clicked() {
+                    match self.export() {
+                        Ok(()) => done = true,
+                        Err(err) => error = Some(err),
+                    }
+                }
+            });
+        self.export_dialog = (open && !done).then_some(error);
+    }
+
+    fn ui_file_menu(&mut self, ui: &mut Ui) {
+        let ctx = ui.ctx().clone();
+        if menu_item(ui, "New", self.keymap.shortcut(Action::New)) {
+            ui.close_menu();
+            self.request(&ctx, DiscardingAction::New);
+        }
+        if menu_item(ui, "Open...", self.keymap.shortcut(Action::Open)) {
+            ui.close_menu();
+            self.request(&ctx, DiscardingAction::Open(None));
+        }
+        ui.add_enabled_ui(!self.recent_files.is_empty(), |ui| {
+            ui.menu_button("Open Recent", |ui| {
+                let mut opened = None;
+                for path in &self.recent_files {
+                    if ui.button(path.display().to_string()).clicked() {
+                        opened = Some(path.clone());
+                    }
+                }
+                ui.separator();
+                if ui.button("Clear Recent Files").clicked() {
+                    self.recent_files.clear();
+                    ui.close_menu();
+                }
+                if let Some(path) = opened {
+                    ui.close_menu();
+                    self.request(&ctx, DiscardingAction::Open(Some(path)));
+                }
+            });
+        });
+        ui.separator();
+        if menu_item(ui, "Save", self.keymap.shortcut(Action::Save)) {
+            ui.close_menu();
+            self.save();
+        }
+        if menu_item(ui, "Save As...", self.keymap.shortcut(Action::SaveAs)) {
+            ui.close_menu();
+            self.save_as();
+        }
+        if menu_item(ui, "Export...", self.keymap.shortcut(Action::Export)) {
+            ui.close_menu();
+            self.export_dialog = Some(None);
+        }
+        ui.separator();
+        if ui.button("Save Log...").on_hover_text("Save every operation since the start, to reproduce the drawing").clicked() {
+            ui.close_menu();
+            self.save_log();
+        }
+        if ui.button("Replay Log...").clicked() {
+            ui.close_menu();
+            self.replay_log();
+        }
+        ui.menu_button("Timelapse", |ui| self.ui_timelapse(ui));
+        ui.separator();
+        if ui.button("Quit").clicked() {
+            ui.close_menu();
+            self.request(&ctx, DiscardingAction::Quit);
+        }
+    }
+
+    fn ui_unsaved_changes(&mut self, ctx: &Context) {
+        if self.pending_action.is_none() {
+            return;
+        }
+        // Some(true) to save first, Some(false) to discard the changes
+        let mut save = None;
+        let mut cancel = false;
+        let modal = Modal::new(Id::new("unsaved_changes")).show(ctx, |ui| {
+            ui.heading("Unsaved changes");
+            ui.label(format!("Do you want to save the changes made to {}?", self.title().trim_end_matches('*')));
+            ui.horizontal(|ui| {
+                if ui.button("Save").clicked() {
+                    save = Some(true);
+                }
+                if ui.button("Don't Save").clicked() {
+                    save = Some(false);
+                }
+                cancel = ui.button("Cancel").clicked();
+            });
+        });
+        if cancel || modal.should_close() {
+            self.pending_action = None;
+            return;
+        }
+        let Some(save) = save else {
+            return;
+        };
+        let action = self.pending_action.take().unwrap();
+        // the action is dropped if the user backs out of the save dialog
+        if !save || self.save() {
+            self.perform(ctx, action);
+        }
+    }
+
+    fn save_log(&mut self) {
+        let Some(log) = self.image.operation_log() else {
+            return;
+        };
+        let Some(path) = rfd::FileDialog::new().add_filter("Operation log", &["json"]).save_file() else {
+            return;
+        };
+        if let Err(err) = log.save(&path) {
+            println!("Couldn't save the operation log, reason: {:?}", err);
+        }
+    }
+
+    fn replay_log(&mut self) {
+        let Some(path) = rfd::FileDialog::new().add_filter("Operation log", &["json"]).pick_file() else {
+            return;
+        };
+        match OperationLog::open(&path).and_then(|log| log.replay()) {
+            Ok(image) => {
+                self.set_image(image, None);
+                self.unsaved_changes = true;
+            },
+            Err(err) => println!("Couldn't replay the operation log, reason: {:?}", err),
+        }
+    }
+
+    /// A dropped file opens as the drawing if it is empty, else it is pasted where it is dropped. Only the first
+    /// of several dropped files is used
+    fn drop_files(&mut self, ctx: &Context) {
+        let dropped = ctx.input_mut(|i| std::mem::take(&mut i.raw.dropped_files));
+        let mut paths = dropped.into_iter().filter_map(|file| file.path);
+        let Some(path) = paths.next() else {
+            return;
+        };
+        // floating another file would commit the previous one into the drawing
+        for ignored in paths {
+            println!("Only one file can be dropped at once, ignored {}", ignored.display());
+        }
+        if self.image.is_empty() && self.floating.is_none() {
+            self.request(ctx, DiscardingAction::Open(Some(path)));
+            return;
+        }
+        match document::open(&path) {
+            Ok(image) => {
+                let center = self.hover_pos
+                    .unwrap_or(glam::Vec2::new(self.camera.center().x, self.camera.center().y));
+                self.float_image(ctx, ClipboardContent::Canvas(Box::new(image)), center);
+            },
+            Err(err) => println!("Couldn't open {}, reason: {:?}", path.display(), err),
+        }
+    }
+
+    /// Darkens the window while files are dragged over it, telling what dropping them does
+    fn ui_file_hover(&self, ctx: &Context) {
+        if ctx.input(|i| i.raw.hovered_files.is_empty()) {
+            return;
+        }
+        let text = if self.image.is_empty() && self.floating.is_none() { "Drop to open" } else { "Drop to paste" };
+        let painter = ctx.layer_painter(LayerId::new(Order::Foreground, Id::new("file_hover")));
+        let screen_rect = ctx.screen_rect();
+        painter.rect_filled(screen_rect, 0., Color32::from_black_alpha(160));
+        painter.text(
+            screen_rect.center(),
+            Align2::CENTER_CENTER,
+            text,
+            TextStyle::Heading.resolve(&ctx.style()),
+            Color32::WHITE,
+        );
+    }
+
+    /// The actions whose shortcut was pressed this frame, or the new shortcut of the action being rebound
+    fn pressed_actions(&mut self, ctx: &Context) -> Vec<Action> {
+        // single keys are left to the text fields, as are the clipboard shortcuts
+        let typing = ctx.wants_keyboard_input();
+        // the raw events keep the keys egui consumed. The clipboard shortcuts come as copy and paste events
+        // without their key event, they're turned back into their key with the held modifiers
+        let pressed = ctx.input(|i| i.raw.events.iter()
+            .filter_map(|event| match event {
+                Event::Key { key, pressed: true, repeat, modifiers, .. } => Some((*key, *modifiers, *repeat)),
+                Event::Copy if !typing => Some((Key::C, i.modifiers, false)),
+                Event::Cut if !typing => Some((Key::X, i.modifiers, false)),
+                Event::Paste(_) if !typing => Some((Key::V, i.modifiers, false)),
+                _ => None,
+            })
+            .collect::<Vec<_>>());
+        if let Some(action) = self.rebinding {
+            if let Some(&(key, modifiers, _)) = pressed.first() {
+                self.rebinding = None;
+                if key == Key::Escape {
+                    ctx.input_mut(|i| i.consume_key(Modifiers::NONE, Key::Escape));
+                } else {
+                    self.rebind(action, Some(keymap::pressed_chord(key, modifiers)));
+                }
+            }
+            return Vec::new();
+        }
+        pressed.into_iter()
+            .filter_map(|(key, modifiers, repeat)| {
+                let action = self.keymap.action(key, modifiers)?;
+                let chord = self.keymap.shortcut(action)?;
+                let plain_key = !chord.modifiers.command && !chord.modifiers.alt;
+                ((!repeat || action.repeats()) && !(typing && plain_key)).then_some(action)
+            })
+            .collect()
+    }
+
+    fn run_action(&mut self, ctx: &Context, action: Action) {
+        match action {
+            Action::New => self.request(ctx, DiscardingAction::New),
+            Action::Open => self.request(ctx, DiscardingAction::Open(None)),
+            Action::Save => {
+                self.save();
+            },
+            Action::SaveAs => {
+                self.save_as();
+            },
+            Action::Export => self.export_dialog = Some(None),
+            Action::Copy => self.copy(),
+            Action::Paste => self.paste(ctx),
+            Action::PasteAsNew => self.request(ctx, DiscardingAction::PasteAsNew),
+            Action::Undo => self.undo(),
+            Action::Redo => self.redo(),
+            Action::SelectionTool => self.set_tool(Tool::Selection),
+            Action::FillTool => self.set_tool(Tool::Fill),
+            Action::BrushTool => self.set_tool(Tool::Brush),
+            Action::EraserTool => self.set_tool(Tool::Eraser),
+            Action::LineTool => self.set_tool(Tool::Shape(ShapeKind::Line)),
+            Action::RectangleTool => self.set_tool(Tool::Shape(ShapeKind::Rectangle)),
+            Action::EllipseTool => self.set_tool(Tool::Shape(ShapeKind::Ellipse)),
+            Action::PolygonTool => self.set_tool(Tool::Shape(ShapeKind::Polygon)),
+            Action::GradientTool => self.set_tool(Tool::Gradient),
+            Action::BrushSizeUp => self.set_stroke_width(self.stroke_width + (self.stroke_width/5).max(1)),
+            Action::BrushSizeDown => self.set_stroke_width(self.stroke_width.saturating_sub((self.stroke_width/5).max(1))),
+            Action::ZoomIn => self.zoom(ZOOM_STEP),
+            Action::ZoomOut => self.zoom(1./ZOOM_STEP),
+            Action::FitToWindow => self.fit_to_window(),
+            Action::ActualPixels => self.actual_pixels(ctx),
+            Action::RotateLeft => self.rotate_view(-ROTATION_STEP),
+            Action::RotateRight => self.rotate_view(ROTATION_STEP),
+            Action::ResetRotation => self.view.rotation = 0.,
+            Action::FlipView => self.view.flipped = !self.view.flipped,
+            Action::TogglePixelGrid => self.pixel_grid = !self.pixel_grid,
+            Action::SwapColors => std::mem::swap(&mut self.stroke_color, &mut self.secondary_color),
+        }
+    }
+
+    fn set_tool(&mut self, tool: Tool) {
+        if tool != self.tool {
+            self.cancel_shape();
+            self.tool = tool;
+        }
+    }
+
+    fn set_stroke_width(&mut self, stroke_width: u32) {
+        self.stroke_width = stroke_width.clamp(1, 100);
+        self.update_brush();
+    }
+
+    /// Rebuilds the brush from the size and the pixel art settings
+    fn update_brush(&mut self) {
+        if self.pixel_art {
+            self.brush = pixel_brush(self.stroke_width as usize);
+        } else {
+            self.brush = round_brush(self.stroke_width as usize+1);
+            self.brush.subpixel = self.subpixel;
+        }
+        self.brush_stroke.pixel_perfect = self.pixel_art && self.pixel_perfect && self.brush.width() == 1;
+    }
+
+    /// Zooms the view around the pointer, or around its center if the pointer is elsewhere,
+    /// a factor above 1 zooms in
+    fn zoom(&mut self, factor: f32) {
+        let scale = self.scene_transform.scaling;
+        if !scale.is_finite() || scale <= 0. {
+            return;
+        }
+        let factor = factor.clamp(ZOOM_RANGE.min/scale, ZOOM_RANGE.max/scale);
+        let fixed = self.view_pointer.unwrap_or(self.camera.center());
+        self.camera = Rect::from_min_max(fixed + (self.camera.min - fixed)/factor, fixed + (self.camera.max - fixed)/factor);
+    }
+
+    /// Shows the whole canvas, as big as the view allows
+    fn fit_to_window(&mut self) {
+        let canvas_rect = Rect::from_min_size(Pos2::ZERO, vec2(self.image.width() as f32, self.image.height() as f32));
+        self.view.center = canvas_rect.center();
+        self.camera = self.view.bounding_rect(canvas_rect);
+    }
+
+    /// Rotates the view clockwise, wrapping to -180..180 degrees
+    fn rotate_view(&mut self, degrees: f32) {
+        let rotation = (self.view.rotation.to_degrees() + degrees + 180.).rem_euclid(360.) - 180.;
+        self.view.rotation = rotation.to_radians();
+    }
+
+    /// Zooms so one canvas pixel covers one screen pixel, keeping the center of the view
+    fn actual_pixels(&mut self, ctx: &Context) {
+        if self.view_rect.is_positive() {
+            self.camera = Rect::from_center_size(self.camera.center(), self.view_rect.size()*ctx.pixels_per_point());
+        }
+    }
+
+    /// Reverts the last change of the drawing, a floating paste or an unfinished shape is dropped first
+    fn undo(&mut self) {
+        if self.floating.take().is_some() {
+            return;
+        }
+        self.cancel_shape();
+        self.gradient_line = None;
+        self.brush_stroke.clear_stroke();
+        self.dragging = false;
+        match self.image.undo() {
+            Ok(true) => self.unsaved_changes = true,
+            Ok(false) => {},
+            Err(err) => println!("Couldn't undo, reason: {:?}", err),
+        }
+        self.render_texture.set(to_color_image(&self.image.render()), TextureOptions::NEAREST);
+    }
+
+    /// Applies the last undone change again, not while a paste is floating since committing it drops the undone changes
+    fn redo(&mut self) {
+        if self.floating.is_some() {
+            return;
+        }
+        self.cancel_shape();
+        self.brush_stroke.clear_stroke();
+        self.dragging = false;
+        match self.image.redo() {
+            Ok(true) => self.unsaved_changes = true,
+            Ok(false) => {},
+            Err(err) => println!("Couldn't redo, reason: {:?}", err),
+        }
+        self.render_texture.set(to_color_image(&self.image.render()), TextureOptions::NEAREST);
+    }
+
+    fn rebind(&mut self, action: Action, shortcut: Option<KeyboardShortcut>) {
+        self.keymap.set(action, shortcut);
+        if let Err(err) = self.keymap.save() {
+            println!("Couldn't save the keymap, reason: {:?}", err);
+        }
+    }
+
+    /// Lists the shortcut of every action, clicking one waits for the new keys
+    fn ui_keymap(&mut self, ctx: &Context) {
+        let mut open = self.keymap_window;
+        Window::new("Keyboard Shortcuts").open(&mut open).resizable(false).show(ctx, |ui| {
+            ScrollArea::vertical().max_height(400.).show(ui, |ui| {
+                Grid::new("keymap").striped(true).show(ui, |ui| {
+                    for action in Action::ALL {
+                        ui.label(action.name());
+                        let text = if self.rebinding == Some(action) {
+                            "Press keys, Escape to cancel".to_string()
+                        } else {
+                            self.keymap.shortcut(action).map_or("None".to_string(), |shortcut| ctx.format_shortcut(&shortcut))
+                        };
+                        let button = ui.add(Button::new(text).selected(self.rebinding == Some(action)));
+                        if button.clicked() {
+                            // otherwise Enter and Space would click the button again instead of being bound
+                            button.surrender_focus();
+                            self.rebinding = Some(action);
+                        }
+                        if ui.button("Clear").clicked() {
+                            self.rebind(action, None);
+                        }
+                        if ui.add_enabled(
+                            self.keymap.shortcut(action) != Some(action.default_shortcut()),
+                            Button::new("Reset")
+                        ).clicked() {
+                            self.rebind(action, Some(action.default_shortcut()));
+                        }
+                        let conflicts = self.keymap.conflicts_with(action);
+                        if conflicts.is_empty() {
+                            ui.label("");
+                        } else {
+                            let names = conflicts.iter().map(|other| other.name()).collect::<Vec<_>>().join(", ");
+                            ui.colored_label(ui.visuals().error_fg_color, format!("Also used by {names}"));
+                        }
+                        ui.end_row();
+                    }
+                });
+            });
+            if ui.button("Reset All").clicked() {
+                for action in Action::ALL {
+                    self.rebind(action, Some(action.default_shortcut()));
+                }
+            }
+        });
+        self.keymap_window = open;
+        if !open {
+            self.rebinding = None;
         }
     }
 
-    fn paste(&mut self) {
-        let Ok(img) = self.clipboard.get_image() else {
+    fn ui_recovery(&mut self, ctx: &Context) {
+        if self.recovery_files.is_empty() {
             return;
+        }
+        let mut restored = None;
+        let mut discard_all = false;
+        let mut discarded = Vec::new();
+        Window::new("Recover unsaved drawings")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(Align2::CENTER_CENTER, Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label("The app didn't close properly, these drawings were autosaved:");
+                for (i, path) in self.recovery_files.iter().enumerate() {
+                    ui.horizontal(|ui| {
+                        let modified = path.metadata().and_then(|meta| meta.modified()).ok()
+                            .and_then(|modified| modified.elapsed().ok())
+                            .map_or("unknown date".to_string(), |elapsed| format!("{} minutes ago", elapsed.as_secs()/60));
+                        ui.label(modified);
+                        if ui.button("Restore").clicked() {
+                            restored = Some(i);
+                        }
+                        if ui.button("Discard").clicked() {
+                            discarded.push(i);
+                        }
+                    });
+                }
+                if ui.button("Discard all").clicked() {
+                    discard_all = true;
+                }
+            });
+        if let Some(i) = restored {
+            match document::open(&self.recovery_files[i]) {
+                Ok(image) => {
+                    self.set_image(image, None);
+                    self.unsaved_changes = true;
+                    // this session's autosave takes over
+                    discarded.push(i);
+                },
+                Err(err) => println!("Couldn't restore the drawing, reason: {:?}", err),
+            }
+        }
+        // a single pass, so the indices clicked this frame stay valid
+        let mut i = 0;
+        self.recovery_files.retain(|path| {
+            let remove = discard_all || discarded.contains(&i);
+            if remove {
+                autosave::remove_recovery_file(path);
+            }
+            i += 1;
+            !remove
+        });
+    }
+
+    /// Pastes the clipboard image as a floating paste, centered on the cursor if it is over the canvas,
+    /// else centered in the view
+    fn paste(&mut self, ctx: &Context) {
+        if let Some(content) = self.clipboard.as_mut().and_then(CanvasClipboard::paste) {
+            let center = self.hover_pos.unwrap_or(glam::Vec2::new(self.camera.center().x, self.camera.center().y));
+            self.float_image(ctx, content, center);
+        }
+    }
+
+    fn float_image(&mut self, ctx: &Context, content: ClipboardContent, center: glam::Vec2) {
+        self.commit_floating();
+        self.cancel_shape();
+        let image = content.render().into_owned();
+        let document = match content {
+            ClipboardContent::Canvas(document) => Some(*document),
+            ClipboardContent::Image(_) => None,
         };
-        self.image.add_image((0, 0), &img.bytes, img.width);
+        let size = glam::Vec2::new(image.width() as f32, image.height() as f32);
+        self.floating = Some(FloatingPaste {
+            texture: ctx.load_texture("floating_paste", to_color_image(&image), TextureOptions::NEAREST),
+            image,
+            document,
+            pos: (center - size/2.).round(),
+            scale: 1.,
+            grab: None,
+        });
+    }
+
+    /// Adds the floating paste to the drawing, the part outside of the canvas is dropped
+    fn commit_floating(&mut self) {
+        let Some(floating) = self.floating.take() else {
+            return;
+        };
+        let rect = floating.rect();
+        let (width, height) = (rect.width().round().max(1.) as u32, rect.height().round().max(1.) as u32);
+        let pos = floating.pos.round().as_ivec2();
+        let (left, top) = ((-pos.x).max(0) as u32, (-pos.y).max(0) as u32);
+        if left >= width || top >= height {
+            return;
+        }
+        let pos = (pos.x.max(0) as usize, pos.y.max(0) as usize);
+        if let Some(document) = floating.document {
+            let scaled = if [width, height] == [document.width() as u32, document.height() as u32] {
+                document
+            } else {
+                document.resized(width as usize, height as usize)
+            };
+            let visible = scaled.cropped([left as usize, top as usize], (width-left) as usize, (height-top) as usize);
+            self.image.add_canvas(pos, &visible);
+        } else {
+            let scaled = imageops::resize(&floating.image, width, height, FilterType::Nearest);
+            let visible = imageops::crop_imm(&scaled, left, top, width-left, height-top).to_image();
+            self.image.add_image(pos, visible.as_raw(), visible.width() as usize);
+        }
+        self.unsaved_changes = true;
+        self.render_texture.set(to_color_image(&self.image.render()), TextureOptions::NEAREST);
+    }
+
+    /// Moves and scales the floating paste, by dragging it or its bottom right handle
+    fn ui_floating(&mut self, ui: &mut Ui, to_canvas: impl Fn(Pos2) -> glam::Vec2) {
+        let view = self.view;
+        let Some(floating) = &mut self.floating else {
+            return;
+        };
+        // the handle keeps the same size on screen whatever the zoom
+        let zoom = ui.ctx().layer_transform_to_global(ui.layer_id()).map_or(1., |transform| transform.scaling);
+        let rect = floating.rect();
+        let handle_rect = Rect::from_center_size(view.to_scene(rect.max), Vec2::splat(HANDLE_SIZE/zoom));
+        let body = ui.interact(view.bounding_rect(rect), ui.id().with("floating_paste"), Sense::drag())
+            .on_hover_cursor(CursorIcon::Move);
+        let handle = ui.interact(handle_rect, ui.id().with("floating_paste_handle"), Sense::drag())
+            .on_hover_cursor(CursorIcon::ResizeNwSe);
+        if let Some(pointer) = handle.interact_pointer_pos().filter(|_| handle.dragged()) {
+            let extent = to_canvas(pointer) - floating.pos;
+            let scale = (extent.x/floating.image.width() as f32).max(extent.y/floating.image.height() as f32);
+            floating.scale = scale.max(1./floating.image.width().min(floating.image.height()) as f32);
+        } else if let Some(pointer) = body.interact_pointer_pos().filter(|_| body.dragged()) {
+            let pointer = to_canvas(pointer);
+            let grab = *floating.grab.get_or_insert(pointer - floating.pos);
+            floating.pos = pointer - grab;
+        }
+        if body.drag_stopped() {
+            floating.grab = None;
+        }
+        let rect = floating.rect();
+        view.paint_texture(ui, floating.texture.id(), rect);
+        let stroke = Stroke::new(1./zoom, Color32::from_gray(60));
+        ui.painter().add(epaint::Shape::closed_line(view.quad(rect).to_vec(), stroke));
+        let handle_rect = Rect::from_center_size(view.to_scene(rect.max), Vec2::splat(HANDLE_SIZE/zoom));
+        ui.painter().rect(handle_rect, 0., Color32::WHITE, stroke, StrokeKind::Inside);
+    }
+
+    fn ui_symmetry(&mut self, ui: &mut egui::Ui) {
+        let name = |kind: SymmetryKind| match kind {
+            SymmetryKind::None => "No symmetry",
+            SymmetryKind::Vertical => "Vertical symmetry",
+            SymmetryKind::Horizontal => "Horizontal symmetry",
+            SymmetryKind::Both => "Both symmetries",
+            SymmetryKind::Radial(_) => "Radial symmetry",
+        };
+        let radial = match self.symmetry.kind {
+            SymmetryKind::Radial(n) => SymmetryKind::Radial(n),
+            _ => SymmetryKind::Radial(6),
+        };
+        ComboBox::from_id_salt("symmetry")
+            .selected_text(name(self.symmetry.kind))
+            .show_ui(ui, |ui| {
+                for kind in [SymmetryKind::None, SymmetryKind::Vertical, SymmetryKind::Horizontal, SymmetryKind::Both, radial] {
+                    ui.selectable_value(&mut self.symmetry.kind, kind, name(kind));
+                }
+            });
+        if let SymmetryKind::Radial(n) = &mut self.symmetry.kind {
+            ui.add(DragValue::new(n).range(2..=32).suffix(" copies"));
+        }
+        if self.symmetry.kind != SymmetryKind::None && ui.button("Center")
+            .on_hover_text("Move the symmetry center back to the middle of the canvas, or drag it on the canvas")
+            .clicked() {
+            self.symmetry.center = glam::Vec2::new(self.image.width() as f32, self.image.height() as f32)/2.;
+        }
+    }
+
+    /// Draws the symmetry axes, their center can be dragged
+    fn ui_symmetry_guides(&mut self, ui: &mut Ui, to_canvas: impl Fn(Pos2) -> glam::Vec2) {
+        if self.symmetry.kind == SymmetryKind::None {
+            return;
+        }
+        let view = self.view;
+        let zoom = ui.ctx().layer_transform_to_global(ui.layer_id()).map_or(1., |transform| transform.scaling);
+        let to_scene = |pos: glam::Vec2| view.to_scene(pos2(pos.x, pos.y));
+        let center = to_scene(self.symmetry.center);
+        let handle = ui.interact(
+            Rect::from_center_size(center, Vec2::splat(HANDLE_SIZE/zoom)),
+            ui.id().with("symmetry_center"),
+            Sense::drag()
+        ).on_hover_cursor(CursorIcon::Move);
+        if let Some(pointer) = handle.interact_pointer_pos().filter(|_| handle.dragged()) {
+            let size = glam::Vec2::new(self.image.width() as f32, self.image.height() as f32);
+            // on pixel centers or edges, so mirrored pixel stamps stay on whole pixels
+            self.symmetry.center = ((to_canvas(pointer)*2.).round()/2.).clamp(glam::Vec2::ZERO, size);
+        }
+        let stroke = Stroke::new(1./zoom, Color32::from_rgba_unmultiplied(0, 140, 255, 180));
+        for (start, end) in self.symmetry.axes(self.image.width(), self.image.height()) {
+            ui.painter().extend(epaint::Shape::dashed_line(
+                &[to_scene(start), to_scene(end)], stroke, 6./zoom, 4./zoom
+            ));
+        }
+        let center = to_scene(self.symmetry.center);
+        ui.painter().circle(center, HANDLE_SIZE/2./zoom, Color32::WHITE, stroke);
     }
 
     fn copy(&mut self) {
-        if let Err(err) = self.clipboard.set_image(ImageData {
-            width: self.image.width(),
-            height: self.image.height(),
-            bytes: Cow::Borrowed(self.image.render().as_raw())
-        }) {
+        let Some(clipboard) = &mut self.clipboard else {
+            return;
+        };
+        if let Err(err) = clipboard.copy(&self.image) {
             println!("Couldn't copy the image, reason: {:?}", err);
         }
     }
 
+    fn preview_shape(&mut self) {
+        let Some(shape) = &self.shape else {
+            return;
+        };
+        self.render_texture.set(
+            to_color_image(self.image.preview_shape(&self.brush, to_rgba8(self.stroke_color), shape)), 
+            TextureOptions::NEAREST
+        );
+    }
+
+    /// Drops the shape or gradient being drawn
+    fn cancel_shape(&mut self) {
+        if self.shape.take().is_some() {
+            self.render_texture.set(to_color_image(self.image.clear_preview()), TextureOptions::NEAREST);
+        }
+        if self.gradient_line.take().is_some() {
+            self.render_texture.set(to_color_image(self.image.clear_gradient_preview()), TextureOptions::NEAREST);
+        }
+    }
+
+    fn finish_polygon(&mut self) {
+        let Some(shape) = &mut self.shape else {
+            return;
+        };
+        // the last point is the one following the cursor
+        shape.points.pop();
+        if shape.points.len() < 2 {
+            self.cancel_shape();
+            return;
+        }
+        self.preview_shape();
+        self.image.apply_preview(to_rgba8(self.stroke_color));
+        self.shape = None;
+        self.unsaved_changes = true;
+    }
+
     pub fn ui_control(&mut self, ui: &mut egui::Ui) {
+        let previous_tool = self.tool;
         ui.horizontal(|ui| {
             ui.selectable_value(&mut self.tool, Tool::Selection, "Selection");
             ui.selectable_value(&mut self.tool, Tool::Fill, "Fill");
             ui.selectable_value(&mut self.tool, Tool::Brush, "Brush");
-            ui.add_enabled(self.tool == Tool::Brush, Label::new("Size:"));
+            ui.selectable_value(&mut self.tool, Tool::Eraser, "Eraser");
+            ui.selectable_value(&mut self.tool, Tool::Shape(ShapeKind::Line), "Line");
+            ui.selectable_value(&mut self.tool, Tool::Shape(ShapeKind::Rectangle), "Rectangle");
+            ui.selectable_value(&mut self.tool, Tool::Shape(ShapeKind::Ellipse), "Ellipse");
+            ui.selectable_value(&mut self.tool, Tool::Shape(ShapeKind::Polygon), "Polygon")
+                .on_hover_text("Click to add vertices, double click or Enter to finish, Escape to cancel");
+            ui.add_enabled(
+                matches!(self.tool, Tool::Shape(_)), 
+                Checkbox::new(&mut self.shape_filled, "Filled")
+            );
+            ui.selectable_value(&mut self.tool, Tool::Gradient, "Gradient");
+            let uses_brush = matches!(self.tool, Tool::Brush | Tool::Eraser | Tool::Shape(_));
+            ui.add_enabled(uses_brush, Label::new("Size:"));
+            let mut stroke_width = self.stroke_width;
             if ui.add_enabled(
-                self.tool == Tool::Brush, 
-                Slider::new(&mut self.stroke_width, 1..=100).step_by(2.).logarithmic(true)
+                uses_brush, 
+                Slider::new(&mut stroke_width, 1..=100).step_by(2.).logarithmic(true)
             ).changed() {
-                self.brush = round_brush(self.stroke_width as usize+1);
+                self.set_stroke_width(stroke_width);
+            }
+            let mut brush_changed = ui.add_enabled(
+                uses_brush && !self.pixel_art, 
+                Checkbox::new(&mut self.subpixel, "Sub-pixel")
+            ).on_hover_text("Smooth stamping at fractional positions, disable for pixel art").changed();
+            brush_changed |= ui.add_enabled(uses_brush, Checkbox::new(&mut self.pixel_art, "Pixel art"))
+                .on_hover_text("Hard brushes stamped on whole pixels")
+                .changed();
+            brush_changed |= ui.add_enabled(
+                uses_brush && self.pixel_art && self.stroke_width == 1,
+                Checkbox::new(&mut self.pixel_perfect, "Pixel-perfect")
+            ).on_hover_text("Removes the L-shaped corners of 1 pixel freehand lines").changed();
+            if brush_changed {
+                self.update_brush();
             }
             let mut rgba = Rgba::from(self.stroke_color);
             if color_picker::color_edit_button_rgba(ui, &mut rgba, color_picker::Alpha::OnlyBlend).changed() {
                 let srgba = rgba.to_srgba_unmultiplied();
                 self.stroke_color = Color32::from_rgba_unmultiplied(srgba[0], srgba[1], srgba[2], srgba[3]);
             }
+            let mut rgba = Rgba::from(self.secondary_color);
+            let swap_text = self.keymap.shortcut(Action::SwapColors)
+                .map_or(String::new(), |shortcut| format!(", {} to swap", ui.ctx().format_shortcut(&shortcut)));
+            if color_picker::color_edit_button_rgba(ui, &mut rgba, color_picker::Alpha::OnlyBlend)
+                .on_hover_text(format!("Secondary color{swap_text}"))
+                .changed() {
+                let srgba = rgba.to_srgba_unmultiplied();
+                self.secondary_color = Color32::from_rgba_unmultiplied(srgba[0], srgba[1], srgba[2], srgba[3]);
+            }
+            if self.tool == Tool::Fill {
+                ui.separator();
+                self.ui_fill(ui);
+            }
+            if self.tool == Tool::Gradient {
+                ui.separator();
+                self.ui_gradient(ui);
+            }
+            if matches!(self.tool, Tool::Brush | Tool::Eraser) {
+                ui.separator();
+                self.ui_symmetry(ui);
+            }
+            if self.floating.is_some() {
+                ui.separator();
+                ui.label("Drag to move, drag the corner to scale");
+                if ui.button("Place").on_hover_text("Enter").clicked() {
+                    self.commit_floating();
+                }
+                if ui.button("Cancel").on_hover_text("Escape").clicked() {
+                    self.floating = None;
+                }
+            }
             ui.separator();
             if ui.button("Clear Painting").clicked() {
-                self.image = CanvasImage::new(self.image.width(), self.image.height());
+                self.image.clear();
+                self.unsaved_changes = true;
                 self.render_texture.set(
                     ColorImage::new([self.image.width(), self.image.height()], Color32::TRANSPARENT), 
                     TextureOptions::LINEAR
                 );
             }
         });
+        if self.tool != previous_tool {
+            self.cancel_shape();
+        }
+    }
+
+    fn ui_timelapse(&mut self, ui: &mut egui::Ui) {
+        let options = &mut self.timelapse_options;
+        ui.horizontal(|ui| {
+            ui.label("Every:");
+            ui.add(DragValue::new(&mut options.interval).range(1..=1000).suffix(" operations"));
+        });
+        ui.horizontal(|ui| {
+            ui.label("Frame rate:");
+            ui.add(DragValue::new(&mut options.frame_rate).range(1..=60).suffix(" fps"));
+        });
+        ui.horizontal(|ui| {
+            ui.label("Scale:");
+            ui.add(DragValue::new(&mut options.scale).range(0.1..=8.).speed(0.05).suffix("x"));
+        });
+        if ui.button("Export").clicked() {
+            ui.close_menu();
+            let Some(log) = self.image.operation_log() else {
+                return;
+            };
+            let Some(path) = rfd::FileDialog::new()
+                .add_filter("GIF", &["gif"])
+                .add_filter("Animated PNG", &["png", "apng"])
+                .save_file() else {
+                return;
+            };
+            if let Err(err) = timelapse::export(log, &self.timelapse_options, &path) {
+                println!("Couldn't export the timelapse, reason: {:?}", err);
+            }
+        }
+    }
+
+    fn ui_fill(&mut self, ui: &mut egui::Ui) {
+        ui.label("Tolerance:");
+        ui.add(Slider::new(&mut self.fill_options.tolerance, 0..=u8::MAX));
+        ui.checkbox(&mut self.fill_options.global, "Global")
+            .on_hover_text("Fill every matching pixel instead of only the contiguous ones");
+        ui.checkbox(&mut self.fill_options.diagonal, "Diagonals")
+            .on_hover_text("Spread the fill to diagonal neighbours");
+        ui.label("Close gaps:");
+        ui.add_enabled(!self.fill_options.global, Slider::new(&mut self.fill_options.gap_closing_radius, 0..=8))
+            .on_hover_text("Gaps in the lines up to twice this many pixels wide are treated as closed");
+        ui.label("Expand:");
+        ui.add(Slider::new(&mut self.fill_options.expand_under_edges, 0..=8))
+            .on_hover_text("Grow the fill by this many pixels beneath partially covered pixels, such as anti-aliased lines");
+        ComboBox::from_id_salt("fill_sample")
+            .selected_text(match self.fill_options.sample {
+                FillSample::All => "Sample all",
+                FillSample::CurrentColor => "Sample current color",
+            })
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut self.fill_options.sample, FillSample::All, "Sample all");
+                ui.selectable_value(&mut self.fill_options.sample, FillSample::CurrentColor, "Sample current color");
+            });
+    }
+
+    fn ui_gradient(&mut self, ui: &mut egui::Ui) {
+        ComboBox::from_id_salt("gradient_kind")
+            .selected_text(match self.gradient.kind {
+                GradientKind::Linear => "Linear",
+                GradientKind::Radial => "Radial",
+                GradientKind::Angular => "Angular",
+            })
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut self.gradient.kind, GradientKind::Linear, "Linear");
+                ui.selectable_value(&mut self.gradient.kind, GradientKind::Radial, "Radial");
+                ui.selectable_value(&mut self.gradient.kind, GradientKind::Angular, "Angular");
+            });
+        let mut removed = None;
+        let mut moved = false;
+        let can_remove = self.gradient.stops.len() > 2;
+        for (i, (pos, color)) in self.gradient.stops.iter_mut().enumerate() {
+            let mut rgba = Rgba::from(to_color32(*color));
+            if color_picker::color_edit_button_rgba(ui, &mut rgba, color_picker::Alpha::OnlyBlend).changed() {
+                *color = image::Rgba(rgba.to_srgba_unmultiplied());
+            }
+            moved |= ui.add(DragValue::new(pos).range(0.0..=1.0).speed(0.01)).changed();
+            if can_remove && ui.small_button("-").on_hover_text("Remove stop").clicked() {
+                removed = Some(i);
+            }
+        }
+        if let Some(i) = removed {
+            self.gradient.stops.remove(i);
+        }
+        if ui.small_button("+").on_hover_text("Add a stop with the current color").clicked() {
+            self.gradient.stops.push((0.5, to_rgba8(self.stroke_color)));
+            moved = true;
+        }
+        if moved {
+            self.gradient.sort_stops();
+        }
     }
 
     pub fn ui_content(&mut self, ui: &mut Ui) {
-        // TODO: 
-        // 1. remove the jitter when clamping the camera pos
-        // 2. make Scene panning controlled by middle click or CTRL Click (for tablet pen)
-        // 3. make Scene panning work when cursor is inside canvas (currently doesn't work because allocate_response eats the event ?)
-        self.camera.set_center(self.image.rect().clamp(self.camera.center()));
-        Scene::new().zoom_range(0.1..=8.0).show(ui, &mut self.camera, |ui| {
+        let canvas_rect = Rect::from_min_size(Pos2::ZERO, vec2(self.image.width() as f32, self.image.height() as f32));
+        self.view.center = canvas_rect.center();
+        if !self.camera.is_positive() {
+            self.fit_to_window();
+        }
+        // the scene takes the rest of the panel
+        self.view_rect = ui.available_rect_before_wrap();
+        self.ui_pan(ui);
+        let panning = self.panning;
+        if !ui.input(|i| i.pointer.middle_down() || i.pointer.primary_down()) {
+            self.panning = false;
+        }
+        // the canvas center stays in view. Zooming or panning in the scene can move it out until the next frame
+        self.camera.set_center(self.view.bounding_rect(canvas_rect).clamp(self.camera.center()));
+        // the scene gets its own copy of the camera so the closure can borrow self
+        let mut camera = self.camera;
+        Scene::new().zoom_range(ZOOM_RANGE).show(ui, &mut camera, |ui| {
+            self.scene_transform = ui.ctx().layer_transform_to_global(ui.layer_id()).unwrap_or_default();
+            let view = self.view;
+            // the painting tools start a drag as soon as the button is down, clicking would wait to tell them apart
+            let sense = if self.tool == Tool::Shape(ShapeKind::Polygon) { Sense::click() } else { Sense::drag() };
             let response = ui
-                .allocate_response(self.image.dims(), Sense::drag())
+                .allocate_rect(view.bounding_rect(canvas_rect), sense)
                 .on_hover_cursor(match self.tool {
-                    Tool::Brush => egui::CursorIcon::Crosshair,
+                    Tool::Brush | Tool::Eraser | Tool::Shape(_) | Tool::Gradient => egui::CursorIcon::Crosshair,
                     Tool::Fill => egui::CursorIcon::Cell,
                     Tool::Selection => egui::CursorIcon::Copy,
                 });
-            let to_screen = emath::RectTransform::from_to(
-                Rect::from_min_size(Pos2::ZERO, response.rect.square_proportions()),
-                response.rect,
-            );
-            let from_screen = to_screen.inverse();
-            if response.dragged_by(PointerButton::Primary) {
+            let to_canvas = |pointer_pos: Pos2| {
+                let canvas_pos = view.to_canvas(pointer_pos);
+                glam::Vec2::new(canvas_pos.x, canvas_pos.y)
+            };
+            let constrain = ui.input(|i| i.modifiers.shift);
+            self.hover_pos = response.hover_pos().map(to_canvas);
+            // the tools are paused while a paste is floating or the view is panned
+            let painting = self.floating.is_none() && !panning;
+            if painting && response.dragged_by(PointerButton::Primary) {
                 if let Some(pointer_pos) = response.interact_pointer_pos() {
-                    let mut canvas_pos = from_screen * pointer_pos;
-                    let min_axis = self.image.width().min(self.image.height()) as f32;
-                    canvas_pos.x *= min_axis;
-                    canvas_pos.y *= min_axis;
+                    let canvas_pos = to_canvas(pointer_pos);
                     match self.tool {
                         Tool::Brush => {
                             self.render_texture.set(
-                                self.image.preview_with(
+                                to_color_image(self.image.preview_with(
+                                    &self.brush, 
+                                    to_rgba8(self.stroke_color), 
+                                    self.symmetry.apply(self.brush_stroke.update_stroke(canvas_pos, self.brush.spacing))
+                                )), 
+                                TextureOptions::NEAREST
+                            )
+                        },
+                        Tool::Eraser => {
+                            // the color's opacity doubles as the eraser strength
+                            self.render_texture.set(
+                                to_color_image(self.image.preview_erase(
                                     &self.brush, 
-                                    self.stroke_color, 
-                                    self.brush_stroke.update_stroke(canvas_pos, self.brush.spacing)
-                                ).clone(), 
+                                    self.stroke_color.a(), 
+                                    self.symmetry.apply(self.brush_stroke.update_stroke(canvas_pos, self.brush.spacing))
+                                )), 
                                 TextureOptions::NEAREST
                             )
                         },
-                        Tool::Fill => {
-                            if !self.dragging {
-                                self.render_texture.set(
-                                    self.image.fill(canvas_pos, self.stroke_color).clone(), 
-                                    TextureOptions::NEAREST
-                                );
-                            }
+                        Tool::Shape(kind) if kind != ShapeKind::Polygon => {
+                            self.shape.get_or_insert_with(|| Shape::new(kind, self.shape_filled, canvas_pos))
+                                .move_last(canvas_pos, constrain);
+                            self.preview_shape();
+                        },
+                        // a gradient cancelled with Escape stays cancelled until the next drag
+                        Tool::Gradient if self.gradient_line.is_some() || !self.dragging => {
+                            let start = self.gradient_line.map_or(canvas_pos, |(start, _)| start);
+                            self.gradient_line = Some((start, canvas_pos));
+                            self.render_texture.set(
+                                to_color_image(self.image.preview_gradient(&self.gradient, start, canvas_pos)), 
+                                TextureOptions::NEAREST
+                            );
+                        },
+                        Tool::Fill if !self.dragging => {
+                            self.render_texture.set(
+                                to_color_image(self.image.fill(canvas_pos, to_rgba8(self.stroke_color), &self.fill_options)), 
+                                TextureOptions::NEAREST
+                            );
                         },
                         _ => {}
                     }
@@ -187,51 +1374,241 @@ impl CanvasApp {
                     self.unsaved_changes = true;
                 }    
             }
-            if response.drag_stopped() {
-                self.image.apply_preview(self.stroke_color);
+            if painting && response.drag_stopped() {
+                // the pixel perfect mode holds the last stamp back
+                let remaining = self.symmetry.apply(self.brush_stroke.finish_stroke());
+                if !remaining.is_empty() {
+                    match self.tool {
+                        Tool::Brush => {
+                            self.image.preview_with(&self.brush, to_rgba8(self.stroke_color), remaining);
+                        },
+                        Tool::Eraser => {
+                            self.image.preview_erase(&self.brush, self.stroke_color.a(), remaining);
+                        },
+                        _ => {},
+                    }
+                }
+                match self.tool {
+                    Tool::Eraser => self.image.apply_erase(self.stroke_color.a()),
+                    // polygons are committed on double click or Enter
+                    Tool::Shape(ShapeKind::Polygon) => {},
+                    Tool::Gradient => if let Some((start, end)) = self.gradient_line.take() {
+                        self.image.apply_gradient(&self.gradient, start, end);
+                    },
+                    Tool::Brush | Tool::Shape(_) => {
+                        self.image.apply_preview(to_rgba8(self.stroke_color));
+                        self.shape = None;
+                    },
+                    // fills are applied at once, there's no preview
+                    Tool::Fill | Tool::Selection => {},
+                }
                 self.brush_stroke.clear_stroke();
                 self.dragging = false;
             }
-            Image::from_texture((self.render_texture.id(), self.image.dims()))
-                .bg_fill(Color32::WHITE)
-                .paint_at(&ui, self.image.rect());
+            if painting && self.tool == Tool::Shape(ShapeKind::Polygon) {
+                if response.clicked() {
+                    if let Some(pointer_pos) = response.interact_pointer_pos() {
+                        let canvas_pos = to_canvas(pointer_pos);
+                        match &mut self.shape {
+                            Some(shape) => {
+                                shape.move_last(canvas_pos, constrain);
+                                shape.points.push(shape.points[shape.points.len()-1]);
+                            },
+                            None => self.shape = Some(Shape::new(ShapeKind::Polygon, self.shape_filled, canvas_pos)),
+                        }
+                        self.preview_shape();
+                    }
+                } else if let (Some(shape), Some(hover_pos)) = (&mut self.shape, response.hover_pos()) {
+                    if ui.input(|i| i.pointer.delta() != Vec2::ZERO) {
+                        shape.move_last(to_canvas(hover_pos), constrain);
+                        self.preview_shape();
+                    }
+                }
+                if response.double_clicked() || ui.input(|i| i.key_pressed(Key::Enter)) {
+                    self.finish_polygon();
+                }
+            }
+            if ui.input(|i| i.key_pressed(Key::Escape)) {
+                self.cancel_shape();
+                self.floating = None;
+            }
+            if ui.input(|i| i.key_pressed(Key::Enter)) {
+                self.commit_floating();
+            }
+            ui.painter().add(epaint::Shape::convex_polygon(view.quad(canvas_rect).to_vec(), Color32::WHITE, Stroke::NONE));
+            view.paint_texture(ui, self.render_texture.id(), canvas_rect);
+            if self.pixel_grid {
+                paint_pixel_grid(ui, view, canvas_rect);
+            }
+            if matches!(self.tool, Tool::Brush | Tool::Eraser) {
+                self.ui_symmetry_guides(ui, to_canvas);
+            }
+            self.ui_floating(ui, to_canvas);
             response
         });
+        // the view moves with the pointer only while panning, the scene would drag it along a second time
+        if !panning {
+            self.camera = camera;
+        }
+        self.view_pointer = ui.input(|i| i.pointer.latest_pos())
+            .filter(|pos| self.view_rect.contains(*pos))
+            .map(|pos| self.scene_transform.inverse()*pos);
+    }
+
+    /// Pans the view while the middle button or space and the primary button are held, anywhere in the view
+    fn ui_pan(&mut self, ui: &mut Ui) {
+        let space = ui.input(|i| i.key_down(Key::Space)) && !ui.ctx().wants_keyboard_input();
+        let (pressed, pan_buttons, pointer, delta) = ui.input(|i| (
+            i.pointer.any_pressed(),
+            i.pointer.middle_down() || (space && i.pointer.primary_down()),
+            i.pointer.latest_pos(),
+            i.pointer.delta(),
+        ));
+        let over_view = pointer.is_some_and(|pos| self.view_rect.contains(pos));
+        if pressed && pan_buttons && over_view && !self.dragging {
+            self.panning = true;
+        }
+        if self.panning {
+            self.camera = self.camera.translate(-delta/self.scene_transform.scaling);
+            ui.ctx().set_cursor_icon(CursorIcon::Grabbing);
+        } else if space && over_view {
+            ui.ctx().set_cursor_icon(CursorIcon::Grab);
+        }
     }
 }
 
 impl App for CanvasApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        CentralPanel::default().show(ctx, |ui| {
-            ui.input(|i| {
-                for event in &i.raw.events {
-                    let Event::Key { 
-                        key, physical_key: _, pressed: _, repeat: _, modifiers 
-                    } = event else {
-                        continue;
-                    };
-                    if !modifiers.command {
-                        continue;
+        if ctx.input(|i| i.viewport().close_requested()) && !self.closing && self.unsaved_changes {
+            ctx.send_viewport_cmd(ViewportCommand::CancelClose);
+            self.pending_action = Some(DiscardingAction::Quit);
+        }
+        let mut actions = self.pressed_actions(ctx);
+        TopBottomPanel::top("menu_bar").show(ctx, |ui| {
+            menu::bar(ui, |ui| {
+                ui.menu_button("File", |ui| self.ui_file_menu(ui));
+                ui.menu_button("View", |ui| {
+                    for action in [Action::ZoomIn, Action::ZoomOut, Action::FitToWindow, Action::ActualPixels] {
+                        if menu_item(ui, action.name(), self.keymap.shortcut(action)) {
+                            ui.close_menu();
+                            actions.push(action);
+                        }
                     }
-                    if key == &Key::S && self.unsaved_changes {
-                        self.unsaved_changes = false;
-                        self.save();
-                    } else if key == &Key::V {
-                        // Serious performance issues (kinda expected), need to chunk the color presences
-                        self.paste();
-                        self.render_texture.set(self.image.render(), TextureOptions::NEAREST);
-                    } else if key == &Key::C {
-                        self.copy();
+                    ui.separator();
+                    for action in [Action::RotateLeft, Action::RotateRight, Action::ResetRotation, Action::FlipView] {
+                        if menu_item(ui, action.name(), self.keymap.shortcut(action)) {
+                            ui.close_menu();
+                            actions.push(action);
+                        }
                     }
-                }
+                    ui.separator();
+                    let text = if self.pixel_grid { "Hide Pixel Grid" } else { "Show Pixel Grid" };
+                    let shortcut_text = self.keymap.shortcut(Action::TogglePixelGrid)
+                        .map_or(String::new(), |shortcut| ui.ctx().format_shortcut(&shortcut));
+                    if ui.add(Button::new(text).shortcut_text(shortcut_text))
+                        .on_hover_text(format!("Shown from {PIXEL_GRID_MIN_ZOOM}x zoom"))
+                        .clicked() {
+                        ui.close_menu();
+                        actions.push(Action::TogglePixelGrid);
+                    }
+                    let mut degrees = self.view.rotation.to_degrees();
+                    if ui.add(Slider::new(&mut degrees, -180.0..=180.).suffix("°").text("Rotation")).changed() {
+                        self.view.rotation = degrees.to_radians();
+                    }
+                    ui.checkbox(&mut self.view.flipped, "Flipped");
+                });
+                ui.menu_button("Edit", |ui| {
+                    for action in [Action::Undo, Action::Redo, Action::Copy, Action::Paste, Action::PasteAsNew] {
+                        if menu_item(ui, action.name(), self.keymap.shortcut(action)) {
+                            ui.close_menu();
+                            actions.push(action);
+                        }
+                    }
+                    ui.separator();
+                    if ui.button("Keyboard Shortcuts...").clicked() {
+                        ui.close_menu();
+                        self.keymap_window = true;
+                    }
+                });
             });
+        });
+        for action in actions {
+            self.run_action(ctx, action);
+        }
+        // space pans the view, it shouldn't also press the last clicked button
+        if ctx.input(|i| i.key_pressed(Key::Space)) && !ctx.wants_keyboard_input() {
+            ctx.memory_mut(|memory| memory.focused().map(|id| memory.surrender_focus(id)));
+        }
+        CentralPanel::default().show(ctx, |ui| {
             self.ui_control(ui);
             self.ui_content(ui);
         });
+        self.drop_files(ctx);
+        self.ui_file_hover(ctx);
+        self.ui_keymap(ctx);
+        self.ui_recovery(ctx);
+        self.ui_unsaved_changes(ctx);
+        self.ui_export(ctx);
+        if let Some(delay) = self.autosave.tick(&self.image) {
+            ctx.request_repaint_after(delay);
+        }
         let title = self.title();
         if title != self.last_title {
             ctx.send_viewport_cmd(ViewportCommand::Title(self.title()));
             self.last_title = title;
         }
     }
-}
\ No newline at end of file
+
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        eframe::set_value(storage, RECENT_FILES_KEY, &self.recent_files);
+    }
+
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        self.autosave.discard();
+    }
+}
+
+fn menu_item(ui: &mut Ui, text: &str, shortcut: Option<KeyboardShortcut>) -> bool {
+    let shortcut_text = shortcut.map_or(String::new(), |shortcut| ui.ctx().format_shortcut(&shortcut));
+    ui.add(Button::new(text).shortcut_text(shortcut_text)).clicked()
+}
+
+/// Lines between the visible pixels, once they are big enough on screen
+fn paint_pixel_grid(ui: &Ui, view: ViewTransform, canvas_rect: Rect) {
+    let zoom = ui.ctx().layer_transform_to_global(ui.layer_id()).map_or(1., |transform| transform.scaling);
+    if zoom < PIXEL_GRID_MIN_ZOOM {
+        return;
+    }
+    // the clip rect is the part of the scene in view
+    let clip = ui.clip_rect();
+    let visible = Rect::from_points(&[clip.left_top(), clip.right_top(), clip.right_bottom(), clip.left_bottom()]
+        .map(|pos| view.to_canvas(pos)))
+        .intersect(canvas_rect);
+    if !visible.is_positive() {
+        return;
+    }
+    let stroke = Stroke::new(1./zoom, Color32::from_rgba_unmultiplied(128, 128, 128, 96));
+    let (x0, x1) = (visible.min.x.floor() as i32, visible.max.x.ceil() as i32);
+    let (y0, y1) = (visible.min.y.floor() as i32, visible.max.y.ceil() as i32);
+    for x in x0..=x1 {
+        let (top, bottom) = (pos2(x as f32, y0 as f32), pos2(x as f32, y1 as f32));
+        ui.painter().line_segment([view.to_scene(top), view.to_scene(bottom)], stroke);
+    }
+    for y in y0..=y1 {
+        let (left, right) = (pos2(x0 as f32, y as f32), pos2(x1 as f32, y as f32));
+        ui.painter().line_segment([view.to_scene(left), view.to_scene(right)], stroke);
+    }
+}
+
+/// The engine works with unmultiplied colors
+fn to_rgba8(color: Color32) -> image::Rgba<u8> {
+    image::Rgba(color.to_srgba_unmultiplied())
+}
+
+fn to_color32(color: image::Rgba<u8>) -> Color32 {
+    Color32::from_rgba_unmultiplied(color[0], color[1], color[2], color[3])
+}
+
+fn to_color_image(render: &RgbaImage) -> ColorImage {
+    ColorImage::from_rgba_unmultiplied([render.width() as usize, render.height() as usize], render.as_raw())
+}
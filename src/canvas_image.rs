@@ -1,16 +1,23 @@
-use std::{collections::{BTreeMap, HashSet}, u8};
+use std::{borrow::Cow, collections::{BTreeMap, HashSet}, mem};
 
-use eframe::egui::{self, Color32, ColorImage, Pos2, Rect, Vec2};
-use glam::IVec2;
+use anyhow::{bail, Result};
+use glam::{IVec2, Vec2};
+use image::{Rgba, RgbaImage};
 use grid::Grid;
+use serde::{Deserialize, Serialize};
 
-use crate::{brush::Brush, raster::Raster, vec_map::VecMap};
+use crate::{
+    brush::Brush, document, gradient::Gradient, mask, operation_log::{apply_operation, Operation, OperationLog},
+    raster::Raster, shape::Shape, vec_map::VecMap
+};
 
 pub struct CanvasImage {
     colors: VecMap<[u8; 4], Raster>,
-    cached_render: ColorImage,
+    cached_render: RgbaImage,
     current_stroke: Raster,
     dims: [usize; 2],
+    /// Every modification is recorded while this is set
+    log: Option<OperationLog>,
 }
 
 impl CanvasImage {
@@ -19,16 +26,200 @@ impl CanvasImage {
             colors: VecMap(Vec::new()),
             current_stroke: Raster(Grid::new(width, height)),
             dims: [width, height],
-            cached_render: ColorImage::new([width, height], Color32::TRANSPARENT),
+            cached_render: RgbaImage::new(width as u32, height as u32),
+            log: None,
         }
     }
 
-    pub fn render(&self) -> ColorImage {
+    /// Starts recording every modification, the current content is kept as the starting point of the log
+    pub fn start_recording(&mut self) {
+        let mut log = OperationLog::new(self.dims[0], self.dims[1]);
+        if !self.colors.0.is_empty() {
+            let mut bytes = Vec::new();
+            document::write_to(self, &mut bytes).expect("writing to memory can't fail");
+            log.push(Operation::Snapshot(bytes));
+        }
+        self.log = Some(log);
+    }
+
+    pub fn stop_recording(&mut self) -> Option<OperationLog> {
+        self.log.take()
+    }
+
+    pub fn operation_log(&self) -> Option<&OperationLog> {
+        self.log.as_ref()
+    }
+
+    /// Reverts the last change, only possible while recording. The canvas is restored from the nearest
+    /// checkpoint of the log and the operations since then are replayed. Returns whether there was a change to revert
+    pub fn undo(&mut self) -> Result<bool> {
+        let Some(log) = &mut self.log else {
+            return Ok(false);
+        };
+        if !log.pop_applied() {
+            return Ok(false);
+        }
+        let mut image = log.replay_from_checkpoint()?;
+        image.log = self.log.take();
+        *self = image;
+        Ok(true)
+    }
+
+    /// Applies the last undone change again, until a new change is made. Returns whether there was one
+    pub fn redo(&mut self) -> Result<bool> {
+        let Some(log) = &mut self.log else {
+            return Ok(false);
+        };
+        let Some(operations) = log.redo.pop() else {
+            return Ok(false);
+        };
+        // applying the operations records them again, which drops the undone changes
+        let redo = mem::take(&mut log.redo);
+        // the change was made without the stroke being previewed now
+        if log.operations.last().is_some_and(|operation| !operation.is_applied()) {
+            self.clear_preview();
+        }
+        let start = self.log.as_ref().map_or(0, |log| log.operations.len());
+        let mut brush = self.log.as_ref().and_then(|log| log.brush_at(start));
+        let result = operations.iter().enumerate()
+            .try_for_each(|(i, operation)| apply_operation(self, operation, &mut brush, start + i));
+        if let Some(log) = &mut self.log {
+            log.redo = redo;
+        }
+        result.map(|_| true)
+    }
+
+    fn record(&mut self, operation: impl FnOnce() -> Operation) {
+        if self.log.is_none() {
+            return;
+        }
+        let operation = operation();
+        self.checkpoint();
+        if let Some(log) = &mut self.log {
+            // a new change drops the undone ones
+            if operation.is_applied() {
+                log.redo.clear();
+            }
+            log.push(operation);
+        }
+    }
+
+    fn record_stamps(&mut self, brush: &Brush, operation: impl FnOnce() -> Operation) {
+        self.checkpoint();
+        if let Some(log) = &mut self.log {
+            log.push_brush(brush);
+            log.push(operation());
+        }
+    }
+
+    /// Keeps the canvas once in a while for undo to start from
+    fn checkpoint(&mut self) {
+        if !self.log.as_ref().is_some_and(OperationLog::wants_checkpoint) {
+            return;
+        }
+        let mut bytes = Vec::new();
+        document::write_to(self, &mut bytes).expect("writing to memory can't fail");
+        if let Some(log) = &mut self.log {
+            log.push_checkpoint(bytes);
+        }
+    }
+
+    /// Removes every color, the canvas keeps its size
+    pub fn clear(&mut self) {
+        self.record(|| Operation::Clear);
+        self.colors.0.clear();
+        self.current_stroke = Raster::new(&self.dims);
+        self.update_render();
+    }
+
+    pub fn render(&self) -> RgbaImage {
         self.cached_render.clone()
     }
 
-    fn raster_idx(&mut self, color: Color32) -> usize {
-        let rgba = [color.r(), color.g(), color.b(), color.a()];
+    /// Builds an image from its colors and their presence rasters, which must all be width x height
+    pub fn from_palette(width: usize, height: usize, colors: Vec<(Rgba<u8>, Raster)>) -> Result<Self> {
+        if let Some((color, raster)) = colors.iter().find(|(_, raster)| raster.0.size() != (width, height)) {
+            let (raster_width, raster_height) = raster.0.size();
+            bail!("the raster of {:?} is {raster_width}x{raster_height} instead of {width}x{height}", color.0);
+        }
+        Ok(Self::with_colors(width, height, colors))
+    }
+
+    fn with_colors(width: usize, height: usize, colors: Vec<(Rgba<u8>, Raster)>) -> Self {
+        let mut res = Self::new(width, height);
+        res.colors = VecMap(colors.into_iter().map(|(color, raster)| (color.0, raster)).collect());
+        res.update_render();
+        res
+    }
+
+    /// Every color of the image along with its presence raster
+    pub fn palette(&self) -> impl Iterator<Item = (Rgba<u8>, &Raster)> {
+        self.colors.0.iter().map(|(rgba, raster)| (Rgba(*rgba), raster))
+    }
+
+    /// Every color of the image along with the area it covers, in pixels
+    pub fn coverage(&self) -> Vec<(Rgba<u8>, f32)> {
+        self.palette()
+            .map(|(color, raster)| (
+                color, 
+                raster.0.iter().map(|&presence| presence as u32).sum::<u32>() as f32/u8::MAX as f32
+            ))
+            .collect()
+    }
+
+    /// Whether nothing was drawn, colors can stay in the palette after being erased
+    pub fn is_empty(&self) -> bool {
+        self.colors.0.iter().all(|(_, raster)| raster.0.iter().all(|&presence| presence == 0))
+    }
+
+    /// Replaces a color of the palette by another, merging them if the new color is already used
+    pub fn swap_color(&mut self, from: Rgba<u8>, to: Rgba<u8>) -> bool {
+        let Some(from_idx) = self.colors.position(&from.0) else {
+            return false;
+        };
+        self.record(|| Operation::SwapColor { from: from.0, to: to.0 });
+        match self.colors.position(&to.0) {
+            Some(to_idx) if to_idx != from_idx => {
+                let (_, raster) = self.colors.0.remove(from_idx);
+                let to_idx = self.colors.position(&to.0).unwrap();
+                for (xy, &presence) in raster.0.indexed_iter() {
+                    self.colors[to_idx].0[xy] = self.colors[to_idx].0[xy].saturating_add(presence);
+                }
+            },
+            _ => self.colors.0[from_idx].0 = to.0,
+        }
+        self.update_render();
+        true
+    }
+
+    pub fn cropped(&self, pos: [usize; 2], width: usize, height: usize) -> Self {
+        let colors = self.palette()
+            .map(|(color, raster)| (color, raster.cropped(pos, &[width, height])))
+            .collect();
+        Self::with_colors(width, height, colors)
+    }
+
+    /// Resamples the presences of every color, so the result is still made of the same palette
+    pub fn resized(&self, width: usize, height: usize) -> Self {
+        let mut colors = self.palette()
+            .map(|(color, raster)| (color, raster.resized(&[width, height])))
+            .collect::<Vec<_>>();
+        // the presences of a pixel can't add up past full coverage
+        for x in 0..width {
+            for y in 0..height {
+                let total = colors.iter().map(|(_, raster)| raster.0[(x, y)] as u32).sum::<u32>();
+                if total > u8::MAX as u32 {
+                    for (_, raster) in colors.iter_mut() {
+                        raster.0[(x, y)] = (raster.0[(x, y)] as u32*u8::MAX as u32/total) as u8;
+                    }
+                }
+            }
+        }
+        Self::with_colors(width, height, colors)
+    }
+
+    fn raster_idx(&mut self, color: Rgba<u8>) -> usize {
+        let rgba = color.0;
         match self.colors.position(&rgba) {
             Some(idx) => idx,
             None => {
@@ -41,32 +232,44 @@ impl CanvasImage {
     fn update_render(&mut self) {
         for x in 0..self.dims[0] {
             for y in 0..self.dims[1] {
-                let xy = (x, y);
-                // Render the colors
-                let mut r = 0.;
-                let mut g = 0.;
-                let mut b = 0.;
-                let mut a = 0;
-                for ([cr, cg, cb, _], raster) in self.colors.0.iter() {
-                    if raster.0[xy] == 0 {
-                        continue;
-                    }
-                    let presence = raster.0[xy] as f32/u8::MAX as f32;
-                    r += *cr as f32*presence;
-                    g += *cg as f32*presence;
-                    b += *cb as f32*presence; 
-                    a += raster.0[xy];
-                }
-                self.cached_render[xy] = Color32::from_rgba_unmultiplied(r as u8, g as u8, b as u8, a);
+                self.render_pixel((x, y));
+            }
+        }
+    }
+
+    fn render_pixel(&mut self, xy: (usize, usize)) {
+        // Render the colors
+        let mut r = 0.;
+        let mut g = 0.;
+        let mut b = 0.;
+        let mut a = 0u32;
+        for ([cr, cg, cb, _], raster) in self.colors.0.iter() {
+            if raster.0[xy] == 0 {
+                continue;
             }
+            let presence = raster.0[xy] as f32/u8::MAX as f32;
+            r += *cr as f32*presence;
+            g += *cg as f32*presence;
+            b += *cb as f32*presence; 
+            a += raster.0[xy] as u32;
         }
+        let a = a.min(u8::MAX as u32) as u8;
+        self.cached_render.put_pixel(xy.0 as u32, xy.1 as u32, Rgba([r as u8, g as u8, b as u8, a]));
     }
 
-    fn update_stroke(&mut self, brush: &Brush, poses: Vec<Pos2>) -> HashSet<(usize, usize)> {
+    fn update_stroke(&mut self, brush: &Brush, poses: Vec<Vec2>) -> HashSet<(usize, usize)> {
         let half_brush = IVec2::new(brush.width() as i32/2, brush.height() as i32/2);
         let mut updated_pixels = HashSet::new();
-        for pos in poses.into_iter().map(|p| to_ivec(p)-half_brush) {
-            for ((x, y), &val) in brush.texture.indexed_iter() {
+        for pos in poses {
+            let (pos, texture) = if brush.subpixel {
+                // the center of the brush's middle pixel must land exactly on pos
+                let top_left = pos - half_brush.as_vec2() - 0.5;
+                let origin = top_left.floor();
+                (origin.as_ivec2(), Cow::Owned(brush.shifted_texture(top_left - origin)))
+            } else {
+                (pos.floor().as_ivec2()-half_brush, Cow::Borrowed(&brush.texture))
+            };
+            for ((x, y), &val) in texture.indexed_iter() {
                 if val == 0 {
                     continue;
                 }
@@ -80,44 +283,178 @@ impl CanvasImage {
                 }
                 let xy = (pos.x as usize, pos.y as usize);
                 // update current stroke
-                self.current_stroke.0[xy] = self.current_stroke.0[xy].max(val.clone());
+                self.current_stroke.0[xy] = self.current_stroke.0[xy].max(val);
                 updated_pixels.insert(xy);
             }
         }
         updated_pixels
     }
 
-    pub fn preview_with(&mut self, brush: &Brush, color: Color32, poses: Vec<Pos2>) -> &ColorImage {
-        let raster_i = self.raster_idx(color);
-        let ca = color.a() as f32/u8::MAX as f32;
+    pub fn preview_with(&mut self, brush: &Brush, color: Rgba<u8>, poses: Vec<Vec2>) -> &RgbaImage {
+        self.record_stamps(brush, || Operation::Stamps { color: color.0, poses: poses.clone() });
         // For each unique newly affected pixels
         for xy in self.update_stroke(brush, poses) {
-            // Update the render without modifying the color presences
-            let presence = self.current_stroke.0[xy] as f32*ca/u8::MAX as f32;
-            let pres_mult = (1. - presence)/u8::MAX as f32;
-            let mut r = 0.;
-            let mut g = 0.;
-            let mut b = 0.;
-            let mut a = 0.;
-            for (i, ([cr, cg, cb, _], raster)) in self.colors.0.iter().enumerate() {
-                let r_presence = if pres_mult == 0. {
-                    0.
-                } else {
-                    raster.0[xy] as f32 * pres_mult
-                } + if i == raster_i { presence } else { 0. };
-                if r_presence == 0. {
-                    continue;
-                }
-                r += *cr as f32*r_presence;
-                g += *cg as f32*r_presence;
-                b += *cb as f32*r_presence; 
-                a += r_presence;
+            self.preview_pixel(xy, color);
+        }
+        &self.cached_render
+    }
+
+    /// Replaces the current stroke with a shape, so the preview can follow the shape as it's being dragged
+    pub fn preview_shape(&mut self, brush: &Brush, color: Rgba<u8>, shape: &Shape) -> &RgbaImage {
+        self.record_stamps(brush, || Operation::Shape { color: color.0, shape: shape.clone() });
+        self.discard_stroke();
+        let updated_pixels = if shape.is_area() {
+            let mut updated_pixels = HashSet::new();
+            for (xy, val) in shape.coverage(self.dims) {
+                self.current_stroke.0[xy] = val;
+                updated_pixels.insert(xy);
             }
-            self.cached_render[xy] = Color32::from_rgba_unmultiplied(r as u8, g as u8, b as u8, (a*u8::MAX as f32) as u8);
+            updated_pixels
+        } else {
+            self.update_stroke(brush, shape.outline(brush.spacing))
+        };
+        for xy in updated_pixels {
+            self.preview_pixel(xy, color);
         }
         &self.cached_render
     }
 
+    /// Discards the current stroke and restores the render where it was previewed
+    pub fn clear_preview(&mut self) -> &RgbaImage {
+        self.record(|| Operation::ClearPreview);
+        self.discard_stroke();
+        &self.cached_render
+    }
+
+    fn discard_stroke(&mut self) {
+        let previewed = self.current_stroke.0.indexed_iter()
+            .filter(|(_, &presence)| presence > 0)
+            .map(|(xy, _)| xy)
+            .collect::<Vec<_>>();
+        self.current_stroke = Raster(Grid::new(self.dims[0], self.dims[1]));
+        for xy in previewed {
+            self.render_pixel(xy);
+        }
+    }
+
+    fn preview_pixel(&mut self, xy: (usize, usize), color: Rgba<u8>) {
+        // Update the render without modifying the color presences
+        let ca = color[3] as f32/u8::MAX as f32;
+        let presence = self.current_stroke.0[xy] as f32*ca/u8::MAX as f32;
+        let rgba = self.mixed_pixel(xy, 1. - presence, &[(color, presence)]);
+        self.cached_render.put_pixel(xy.0 as u32, xy.1 as u32, rgba);
+    }
+
+    /// Renders a pixel as if the existing color presences were scaled by `spare` (in [0, 1])
+    /// and the `added` (color, presence in [0, 1]) were applied on top, the added colors don't need to be in the palette
+    fn mixed_pixel(&self, xy: (usize, usize), spare: f32, added: &[(Rgba<u8>, f32)]) -> Rgba<u8> {
+        let pres_mult = spare/u8::MAX as f32;
+        let mut r = 0.;
+        let mut g = 0.;
+        let mut b = 0.;
+        let mut a = 0.;
+        let existing = self.colors.0.iter().map(|(rgba, raster)| (*rgba, if pres_mult == 0. {
+            0.
+        } else {
+            raster.0[xy] as f32 * pres_mult
+        }));
+        let added = added.iter().map(|(color, presence)| (color.0, *presence));
+        for ([cr, cg, cb, _], r_presence) in existing.chain(added) {
+            if r_presence == 0. {
+                continue;
+            }
+            r += cr as f32*r_presence;
+            g += cg as f32*r_presence;
+            b += cb as f32*r_presence; 
+            a += r_presence;
+        }
+        Rgba([r as u8, g as u8, b as u8, (a*u8::MAX as f32) as u8])
+    }
+
+    /// Previews the erasing of every color under the stroke, `opacity` is how much presence is removed at full brush pressure
+    pub fn preview_erase(&mut self, brush: &Brush, opacity: u8, poses: Vec<Vec2>) -> &RgbaImage {
+        self.record_stamps(brush, || Operation::EraseStamps { opacity, poses: poses.clone() });
+        let oa = opacity as f32/u8::MAX as f32;
+        for xy in self.update_stroke(brush, poses) {
+            // Every color presence shrinks by the erased amount
+            let erased = self.current_stroke.0[xy] as f32*oa/u8::MAX as f32;
+            let rgba = self.mixed_pixel(xy, 1. - erased, &[]);
+            self.cached_render.put_pixel(xy.0 as u32, xy.1 as u32, rgba);
+        }
+        &self.cached_render
+    }
+
+    pub fn apply_erase(&mut self, opacity: u8) {
+        self.record(|| Operation::ApplyErase { opacity });
+        let oa = opacity as f32/u8::MAX as f32;
+        for (xy, &presence) in self.current_stroke.0.indexed_iter() {
+            if presence == 0 {
+                continue;
+            }
+            let spare_presence = 1. - presence as f32*oa/u8::MAX as f32;
+            for (_, other_presence) in self.colors.0.iter_mut() {
+                other_presence.0[xy] = (other_presence.0[xy] as f32 * spare_presence) as u8;
+            }
+        }
+        self.current_stroke = Raster(Grid::new(self.dims[0], self.dims[1]));
+    }
+
+    /// The stop index and presence in [0, 1] of the gradient stops covering a pixel
+    fn gradient_mix(gradient: &Gradient, start: Vec2, end: Vec2, xy: (usize, usize)) -> [(usize, f32); 2] {
+        let center = Vec2::new(xy.0 as f32 + 0.5, xy.1 as f32 + 0.5);
+        gradient.weights(gradient.t_at(start, end, center)).map(|(stop, weight)| 
+            (stop, weight*gradient.stops[stop].1[3] as f32/u8::MAX as f32)
+        )
+    }
+
+    /// Previews a gradient over the whole canvas without modifying the color presences
+    pub fn preview_gradient(&mut self, gradient: &Gradient, start: Vec2, end: Vec2) -> &RgbaImage {
+        for x in 0..self.dims[0] {
+            for y in 0..self.dims[1] {
+                let mix = Self::gradient_mix(gradient, start, end, (x, y))
+                    .map(|(stop, presence)| (gradient.stops[stop].1, presence));
+                let presence = mix[0].1 + mix[1].1;
+                let rgba = self.mixed_pixel((x, y), 1. - presence, &mix);
+                self.cached_render.put_pixel(x as u32, y as u32, rgba);
+            }
+        }
+        &self.cached_render
+    }
+
+    /// Discards a gradient preview, restoring the render of the whole canvas
+    pub fn clear_gradient_preview(&mut self) -> &RgbaImage {
+        self.update_render();
+        &self.cached_render
+    }
+
+    /// Applies a gradient over the whole canvas, the gradient is expressed as presences of its stop colors
+    /// instead of creating a new color for every shade, so it remains editable through the palette
+    pub fn apply_gradient(&mut self, gradient: &Gradient, start: Vec2, end: Vec2) {
+        self.record(|| Operation::Gradient {
+            kind: gradient.kind,
+            stops: gradient.stops.iter().map(|(pos, color)| (*pos, color.0)).collect(),
+            start,
+            end,
+        });
+        let stop_rasters = gradient.stops.iter().map(|(_, color)| self.raster_idx(*color)).collect::<Vec<_>>();
+        for x in 0..self.dims[0] {
+            for y in 0..self.dims[1] {
+                let xy = (x, y);
+                let mix = Self::gradient_mix(gradient, start, end, xy)
+                    .map(|(stop, presence)| (stop_rasters[stop], presence));
+                let spare_presence = 1. - (mix[0].1 + mix[1].1);
+                for (_, other_presence) in self.colors.0.iter_mut() {
+                    other_presence.0[xy] = (other_presence.0[xy] as f32 * spare_presence) as u8;
+                }
+                for (raster_i, presence) in mix {
+                    let raster = &mut self.colors.0[raster_i].1;
+                    raster.0[xy] = raster.0[xy].saturating_add((presence*u8::MAX as f32) as u8);
+                }
+            }
+        }
+        self.update_render();
+    }
+
     fn apply_presence(&mut self, pos: (usize, usize), raster_idx: usize, presence: u8) {
         // Check what will be left for the other color after the new color is applied (could be 0)
         let spare_presence = u8::MAX - presence;
@@ -135,9 +472,10 @@ impl CanvasImage {
         self.colors.0[raster_idx].1.0[pos] += presence;
     }
 
-    pub fn apply_preview(&mut self, color: Color32) {
+    pub fn apply_preview(&mut self, color: Rgba<u8>) {
+        self.record(|| Operation::ApplyPreview { color: color.0 });
         let raster_i = self.raster_idx(color);
-        let ca = color.a() as f32/u8::MAX as f32;
+        let ca = color[3] as f32/u8::MAX as f32;
         for (xy, &presence) in self.current_stroke.0.indexed_iter() {
             if presence == 0 {
                 continue;
@@ -161,31 +499,57 @@ impl CanvasImage {
         self.current_stroke = Raster(Grid::new(self.dims[0], self.dims[1]));
     }
 
+    /// Composites rgba pixels over the drawing, each pixel adds the presence of its opaque color
+    /// given by its alpha, the colors beneath shrink like under a stroke
     pub fn add_image(&mut self, pos: (usize, usize), pixel_data: &[u8], width: usize) {
+        self.record(|| Operation::Paste { pos, width, pixels: pixel_data.to_vec() });
         let mut color_idx = BTreeMap::new();
-        let mut _x = 0;
-        let mut _y = 0;
-        for ((x, y), color) in pixel_data.chunks(4).map(|c| {
-            let res = ((_x, _y), c);
-            _x += 1;
-            if _x >= width {
-                _x = 0;
-                _y += 1;
-            }
-            res
-        }) {
-            let xy = (pos.0+x, pos.1+y);
-            if xy.0 >= self.dims[0] || xy.1 >= self.dims[1] {
+        for (i, pixel) in pixel_data.chunks_exact(4).enumerate() {
+            let xy = (pos.0 + i % width, pos.1 + i / width);
+            if xy.0 >= self.dims[0] || xy.1 >= self.dims[1] || pixel[3] == 0 {
                 continue;
             }
-            let color: [u8; 4] = color.try_into().unwrap();
-            let i: usize = *color_idx.entry(color).or_insert_with(|| 
-                self.colors.position(&color).unwrap_or_else(|| {
-                    self.colors.0.push((color.clone(), Raster::new(&self.dims)));
-                    self.colors.0.len()-1
-                })
-            );
-            self.colors[i].0[xy] = u8::MAX;
+            let rgba = [pixel[0], pixel[1], pixel[2], u8::MAX];
+            let raster_idx = *color_idx.entry(rgba).or_insert_with(|| self.raster_idx(Rgba(rgba)));
+            self.apply_presence(xy, raster_idx, pixel[3]);
+        }
+        self.update_render();
+    }
+
+    /// Composites another drawing over this one, keeping its palette and presences. The colors beneath
+    /// shrink by the total presence of the pasted pixel, so pasting on a blank canvas is lossless
+    pub fn add_canvas(&mut self, pos: (usize, usize), other: &CanvasImage) {
+        self.record(|| {
+            let mut bytes = Vec::new();
+            document::write_to(other, &mut bytes).expect("writing to memory can't fail");
+            Operation::PasteCanvas { pos, document: bytes }
+        });
+        // colors without any presence don't make it to the palette
+        let raster_idxs = other.colors.0.iter()
+            .map(|(rgba, raster)| raster.0.iter().any(|&presence| presence > 0).then(|| self.raster_idx(Rgba(*rgba))))
+            .collect::<Vec<_>>();
+        for x in 0..other.dims[0] {
+            for y in 0..other.dims[1] {
+                let xy = (pos.0 + x, pos.1 + y);
+                if xy.0 >= self.dims[0] || xy.1 >= self.dims[1] {
+                    continue;
+                }
+                let total = other.colors.0.iter().map(|(_, raster)| raster.0[(x, y)] as u32).sum::<u32>();
+                if total == 0 {
+                    continue;
+                }
+                let spare_presence = u8::MAX as u32 - total.min(u8::MAX as u32);
+                for (_, presence) in self.colors.0.iter_mut() {
+                    presence.0[xy] = (presence.0[xy] as u32*spare_presence/u8::MAX as u32) as u8;
+                }
+                for ((_, raster), raster_idx) in other.colors.0.iter().zip(&raster_idxs) {
+                    let Some(raster_idx) = *raster_idx else {
+                        continue;
+                    };
+                    let presence = &mut self.colors.0[raster_idx].1.0[xy];
+                    *presence = presence.saturating_add(raster.0[(x, y)]);
+                }
+            }
         }
         self.update_render();
     }
@@ -195,7 +559,7 @@ impl CanvasImage {
             .filter_map(|(rgba, raster)| {
                 let val = raster.0[xy];
                 if val > 0 {
-                    Some(rgba.clone())
+                    Some(*rgba)
                 } else {
                     None
                 }
@@ -203,71 +567,144 @@ impl CanvasImage {
             .collect()
     }
 
-    pub fn fill(&mut self, pos: Pos2, color: Color32) -> &ColorImage {
-        let rgba = [color.r(), color.g(), color.b(), color.a()];
+    pub fn fill(&mut self, pos: Vec2, color: Rgba<u8>, options: &FillOptions) -> &RgbaImage {
+        if pos.cmplt(Vec2::ZERO).any() || pos.x >= self.dims[0] as f32 || pos.y >= self.dims[1] as f32 {
+            return &self.cached_render;
+        }
+        self.record(|| Operation::Fill { pos, color: color.0, options: options.clone() });
+        let rgba = color.0;
         if !self.colors.contains_key(&rgba) {
             self.colors.0.push((rgba, Raster::new(&self.dims)));
         }
+        let raster_idx = self.colors.position(&rgba).unwrap();
         let start = (pos.x as usize, pos.y as usize);
         let start_colors = self.colors_at(start);
-        if start_colors.len() == 0 {
-            let raster_idx = self.colors.position(&rgba).unwrap();
-            self.fill_space(start, |obj, pos| {
-                let mut current_presence = 0;
-                for i in 0..obj.colors.0.len() {
-                    if i == raster_idx {
-                        continue;
-                    }
-                    current_presence += obj.colors[i].0[pos];
-                }
-                if current_presence >= u8::MAX {
-                    return false;
-                }
-                let spare_presence = u8::MAX-current_presence;
-                if obj.colors[raster_idx].0[pos] == spare_presence {
-                    return false;
-                }
-                obj.colors[raster_idx].0[pos] = spare_presence;
-                true
-            });
+        let region = self.fill_region(start, raster_idx, options);
+        let under_edges = if options.expand_under_edges > 0 {
+            // grow the region beneath the partially covered pixels, so the fill tucks under anti-aliased lines
+            let mut filled = Grid::new(self.dims[0], self.dims[1]);
+            for &pos in &region {
+                filled[pos] = true;
+            }
+            mask::grow(&mut filled, |pos| {
+                let current_presence = self.presence_without(pos, raster_idx);
+                current_presence > 0 && current_presence < u8::MAX
+            }, options.expand_under_edges)
+        } else {
+            Vec::new()
+        };
+        if start_colors.is_empty() {
+            for pos in region {
+                self.colors[raster_idx].0[pos] = u8::MAX-self.presence_without(pos, raster_idx);
+            }
         } else {
-            let raster_idx = self.colors.position(&rgba).unwrap();
             let min_alpha = start_colors.iter().map(|c| c[3]).min().unwrap() as f32/u8::MAX as f32;
             let scaling = (rgba[3] as f32/u8::MAX as f32)/min_alpha;
             let source_rasters = start_colors.into_iter().map(|c| self.colors.position(&c).unwrap()).collect::<Vec<_>>();
             if rgba[3] < u8::MAX || scaling < 1. {
-                self.fill_space(start, |obj, pos| {
-                    let min_presence = source_rasters.iter().map(|&idx| obj.colors[idx].0[pos]).min().unwrap();
+                for pos in region {
+                    let min_presence = source_rasters.iter().map(|&idx| self.colors[idx].0[pos]).min().unwrap();
                     if min_presence == 0 {
-                        return false;
+                        continue;
                     }
-                    obj.apply_presence(pos, raster_idx, (min_presence as f32*scaling) as u8);
-                    true        
-                });
+                    self.apply_presence(pos, raster_idx, (min_presence as f32*scaling) as u8);
+                }
             } else {
-                self.fill_space(start, |obj, pos| {
-                    let (min_presence, min_idx) = source_rasters.iter().map(|&idx| (obj.colors[idx].0[pos], idx)).min().unwrap();
+                for pos in region {
+                    let (min_presence, min_idx) = source_rasters.iter().map(|&idx| (self.colors[idx].0[pos], idx)).min().unwrap();
                     if min_presence == 0 {
-                        return false;
-                    }
-                    obj.colors[min_idx].0[pos] = 0;
-                    let mut current_presence = 0;
-                    for i in 0..obj.colors.0.len() {
-                        if i == raster_idx {
-                            continue;
-                        }
-                        current_presence += obj.colors[i].0[pos];
+                        continue;
                     }
-                    let spare_presence = u8::MAX-current_presence;
-                    obj.colors[raster_idx].0[pos] += spare_presence.min((min_presence as f32*scaling) as u8);
-                    true
-                });
+                    self.colors[min_idx].0[pos] = 0;
+                    let spare_presence = u8::MAX-self.presence_without(pos, raster_idx);
+                    self.colors[raster_idx].0[pos] += spare_presence.min((min_presence as f32*scaling) as u8);
+                }
             }
         }
+        for pos in under_edges {
+            self.colors[raster_idx].0[pos] = u8::MAX-self.presence_without(pos, raster_idx);
+        }
         self.update_render();
         &self.cached_render
     }
 
+    /// Sum of the presences of every color except one at a pixel
+    fn presence_without(&self, pos: (usize, usize), raster_idx: usize) -> u8 {
+        let mut current_presence = 0u32;
+        for i in 0..self.colors.0.len() {
+            if i == raster_idx {
+                continue;
+            }
+            current_presence += self.colors[i].0[pos] as u32;
+        }
+        current_presence.min(u8::MAX as u32) as u8
+    }
+
+    /// Finds every pixel to be filled from start. When sampling every color, a pixel is matching if it isn't fully
+    /// covered for an empty start, or if it holds every color of the start otherwise. A pixel also matches if
+    /// the presences of the sampled colors are all within `options.tolerance` of the presences at start
+    fn fill_region(&mut self, start: (usize, usize), raster_idx: usize, options: &FillOptions) -> Vec<(usize, usize)> {
+        let sampled = match options.sample {
+            FillSample::All => (0..self.colors.0.len()).collect::<Vec<_>>(),
+            FillSample::CurrentColor => vec![raster_idx],
+        };
+        let seed = sampled.iter().map(|&i| self.colors[i].0[start]).collect::<Vec<_>>();
+        let tolerance = options.tolerance;
+        let start_colors = (0..self.colors.0.len()).filter(|&i| self.colors[i].0[start] > 0).collect::<Vec<_>>();
+        let like_start = |obj: &Self, pos: (usize, usize)| match options.sample {
+            FillSample::All if start_colors.is_empty() => obj.presence_without(pos, raster_idx) < u8::MAX,
+            FillSample::All => start_colors.iter().all(|&i| obj.colors[i].0[pos] > 0),
+            FillSample::CurrentColor => false,
+        };
+        let matches = |obj: &Self, pos: (usize, usize)| like_start(obj, pos) || sampled.iter().zip(&seed)
+            .all(|(&i, &presence)| obj.colors[i].0[pos].abs_diff(presence) <= tolerance);
+        let mut region = Vec::new();
+        if options.global {
+            for x in 0..self.dims[0] {
+                for y in 0..self.dims[1] {
+                    if matches(self, (x, y)) {
+                        region.push((x, y));
+                    }
+                }
+            }
+        } else if options.gap_closing_radius > 0 {
+            // gaps are closed by flooding on a dilated barrier, the region is then grown back under the dilation
+            let radius = options.gap_closing_radius;
+            let mut barrier = Grid::new(self.dims[0], self.dims[1]);
+            for x in 0..self.dims[0] {
+                for y in 0..self.dims[1] {
+                    barrier[(x, y)] = !matches(self, (x, y));
+                }
+            }
+            let barrier = mask::dilate(&barrier, radius);
+            let mut visited = Grid::new(self.dims[0], self.dims[1]);
+            // if the start is too close to a line, the gap closing would prevent any filling
+            let closed = !barrier[start];
+            self.fill_space(start, options.diagonal, |obj, pos| {
+                if visited[pos] || (closed && barrier[pos]) || !matches(obj, pos) {
+                    return false;
+                }
+                visited[pos] = true;
+                region.push(pos);
+                true
+            });
+            if closed {
+                region.extend(mask::grow(&mut visited, |pos| matches(self, pos), radius));
+            }
+        } else {
+            let mut visited = Grid::new(self.dims[0], self.dims[1]);
+            self.fill_space(start, options.diagonal, |obj, pos| {
+                if visited[pos] || !matches(obj, pos) {
+                    return false;
+                }
+                visited[pos] = true;
+                region.push(pos);
+                true
+            });
+        }
+        region
+    }
+
     /// Fills the biggest horizontal span from seed within [min, max[, returning [left, right[ edges of span
     /// if seed cannot be filled then (seed.x, seed.x) is returned, 
     /// so left < right can be used to check if operation was succesful
@@ -296,37 +733,45 @@ impl CanvasImage {
         (left, right)
     }
 
-    fn fill_space<Func>(&mut self, start: (usize, usize), mut pixel_fill: Func) 
+    /// Flood fills from start, `pixel_fill` must return false for pixels that cannot be filled or were already filled.
+    /// With `diagonal` set, pixels are also connected to their diagonal neighbours
+    fn fill_space<Func>(&mut self, start: (usize, usize), diagonal: bool, mut pixel_fill: Func) 
         where Func: FnMut(&mut Self, (usize, usize)) -> bool
     {
         // special case for the first span
         let start_span = self.fill_span(start, 0, self.dims[0], &mut pixel_fill);
         let mut spans = Vec::new();
-        self.checked_span_add(&mut spans, start_span, start.1, From::Above);
-        self.checked_span_add(&mut spans, start_span, start.1, From::Below);
+        self.checked_span_add(&mut spans, start_span, start.1, From::Above, diagonal);
+        self.checked_span_add(&mut spans, start_span, start.1, From::Below, diagonal);
         while let Some(span) = spans.pop() {
             let child_span = self.fill_span((span.left, span.y), 0, self.dims[0], &mut pixel_fill);
-            self.checked_span_add(&mut spans, child_span, span.y, span.from);
+            self.checked_span_add(&mut spans, child_span, span.y, span.from, diagonal);
             let left = child_span.0;
             let mut right = child_span.1+1;
             while right < span.right {
                 let child_span = self.fill_span((right, span.y), span.left, self.dims[0], &mut pixel_fill);
-                self.checked_span_add(&mut spans, child_span, span.y, span.from);
+                self.checked_span_add(&mut spans, child_span, span.y, span.from, diagonal);
                 right = child_span.1+1;
             }
             right -= 1;
-            self.checked_span_add(&mut spans, (left, span.left - if span.left > 0 { 1 } else { 0 }), span.y, span.from.opposite());
-            self.checked_span_add(&mut spans, (span.right+1, right), span.y, span.from.opposite());
+            self.checked_span_add(&mut spans, (left, span.left - if span.left > 0 { 1 } else { 0 }), span.y, span.from.opposite(), diagonal);
+            self.checked_span_add(&mut spans, (span.right+1, right), span.y, span.from.opposite(), diagonal);
         }
     }
 
-    fn checked_span_add(&self, spans: &mut Vec<FillSpan>, span: (usize, usize), y: usize, dir: From) {
+    fn checked_span_add(&self, spans: &mut Vec<FillSpan>, span: (usize, usize), y: usize, dir: From, diagonal: bool) {
         if span.0 >= span.1 {
             return;
         }
         if span.0 >= self.dims[0] {
             return;
         }
+        // the pixels diagonally adjacent to the span's ends are neighbours too
+        let span = if diagonal {
+            (span.0.saturating_sub(1), (span.1+1).min(self.dims[0]))
+        } else {
+            span
+        };
         match dir {
             From::Above => {
                 if y == 0 {
@@ -357,8 +802,8 @@ impl CanvasImage {
         }
     }
 
-    pub fn dims(&self) -> egui::Vec2 {
-        Vec2::new(self.dims[0] as f32, self.dims[1] as f32)
+    pub fn dims(&self) -> [usize; 2] {
+        self.dims
     }
 
     pub fn width(&self) -> usize {
@@ -368,14 +813,32 @@ impl CanvasImage {
     pub fn height(&self) -> usize {
         self.dims[1]
     }
+}
 
-    pub fn rect(&self) -> egui::Rect {
-        Rect { min: Pos2::ZERO, max: Pos2::new(self.dims[0] as f32, self.dims[1] as f32) }
-    }
+#[derive(Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum FillSample {
+    /// Compare the presences of every color
+    #[default]
+    All,
+    /// Only compare the presence of the color being filled with
+    CurrentColor,
 }
 
-fn to_ivec(pos: Pos2) -> IVec2 {
-    IVec2 { x: pos.x as i32, y: pos.y as i32 }
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct FillOptions {
+    /// Maximum difference between the presences of a pixel and the starting pixel for it to be filled
+    /// even if it doesn't hold the same colors
+    pub tolerance: u8,
+    /// Fill every matching pixel of the canvas instead of only the contiguous ones
+    pub global: bool,
+    /// Spread the fill to diagonal neighbours (8-connectivity) instead of only the direct ones
+    pub diagonal: bool,
+    pub sample: FillSample,
+    /// The lines are thickened by this many pixels on both sides, so gaps up to twice as wide are treated as closed.
+    /// 0 to disable
+    pub gap_closing_radius: usize,
+    /// Grows the filled region by this many pixels beneath partially covered pixels (such as anti-aliased line edges)
+    pub expand_under_edges: usize,
 }
 
 #[derive(Clone, Copy)]
@@ -398,4 +861,333 @@ struct FillSpan {
     right: usize,
     y: usize,
     from: From
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec2;
+    use image::Rgba;
+    use crate::gradient::{Gradient, GradientKind};
+    use super::{CanvasImage, FillOptions, FillSample};
+
+    const INK: Rgba<u8> = Rgba([0, 0, 0, u8::MAX]);
+    const PAINT: Rgba<u8> = Rgba([u8::MAX, 0, 0, u8::MAX]);
+
+    /// Builds a canvas from ascii art, '#' is fully covered by ink, '+' is half covered and '.' is empty
+    fn canvas(rows: &[&str]) -> CanvasImage {
+        let mut canvas = CanvasImage::new(rows[0].len(), rows.len());
+        let ink = canvas.raster_idx(INK);
+        for (y, row) in rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                canvas.colors[ink].0[(x, y)] = match c {
+                    '#' => u8::MAX,
+                    '+' => 128,
+                    _ => 0,
+                };
+            }
+        }
+        canvas
+    }
+
+    /// Ascii art of the pixels reached by the paint
+    fn painted(canvas: &mut CanvasImage) -> Vec<String> {
+        let paint = canvas.raster_idx(PAINT);
+        (0..canvas.height()).map(|y| (0..canvas.width()).map(|x| 
+            if canvas.colors[paint].0[(x, y)] > 0 { 'o' } else { '.' }
+        ).collect()).collect()
+    }
+
+    fn fill(canvas: &mut CanvasImage, pos: (usize, usize), options: FillOptions) -> Vec<String> {
+        canvas.fill(Vec2::new(pos.0 as f32 + 0.5, pos.1 as f32 + 0.5), PAINT, &options);
+        painted(canvas)
+    }
+
+    #[test]
+    pub fn test_add_image() {
+        let mut canvas = canvas(&[
+            "###",
+            "...",
+        ]);
+        // opaque, half transparent and fully transparent red, over the ink and over nothing
+        let pixels = [255, 0, 0, 255, 255, 0, 0, 128, 255, 0, 0, 0];
+        canvas.add_image((0, 0), &pixels, 3);
+        canvas.add_image((0, 1), &pixels, 3);
+        assert_eq!(canvas.palette().map(|(color, _)| color).collect::<Vec<_>>(), [INK, PAINT]);
+        let presences = |xy| canvas.palette().map(|(_, raster)| raster.0[xy]).collect::<Vec<_>>();
+        assert_eq!(presences((0, 0)), [0, u8::MAX]);
+        assert_eq!(presences((1, 0)), [127, 128]);
+        assert_eq!(presences((2, 0)), [u8::MAX, 0]);
+        assert_eq!(presences((0, 1)), [0, u8::MAX]);
+        assert_eq!(presences((1, 1)), [0, 128]);
+        assert_eq!(presences((2, 1)), [0, 0]);
+    }
+
+    #[test]
+    pub fn test_resized() {
+        // the colors meet halfway through the new middle pixel, rounding both would cover it past full
+        let mut image = canvas(&["#."]);
+        let paint = image.raster_idx(PAINT);
+        image.colors[paint].0[(1, 0)] = u8::MAX;
+        let resized = image.resized(3, 1);
+        for x in 0..3 {
+            let total = resized.palette().map(|(_, raster)| raster.0[(x, 0)] as u32).sum::<u32>();
+            assert!(total <= u8::MAX as u32, "pixel {x} is covered {total}");
+        }
+        assert_eq!(resized.render().get_pixel(0, 0)[3], u8::MAX);
+        assert_eq!(resized.render().get_pixel(2, 0)[3], u8::MAX);
+    }
+
+    #[test]
+    pub fn test_add_canvas() {
+        let pasted = canvas(&[
+            "+#",
+            "..",
+        ]);
+        let mut image = CanvasImage::new(3, 2);
+        image.add_canvas((1, 0), &pasted);
+        let presences = |image: &CanvasImage| image.palette().map(|(_, raster)| raster.0.clone()).collect::<Vec<_>>();
+        assert_eq!(presences(&image), presences(&canvas(&[
+            ".+#",
+            "...",
+        ])));
+        // the ink beneath shrinks like under a stroke
+        let mut image = canvas(&[
+            "##",
+            "##",
+        ]);
+        let mut paint = pasted;
+        paint.swap_color(INK, PAINT);
+        image.add_canvas((0, 0), &paint);
+        assert_eq!(image.palette().map(|(_, raster)| raster.0[(0, 0)]).collect::<Vec<_>>(), [127, 128]);
+        assert_eq!(image.palette().map(|(_, raster)| raster.0[(1, 1)]).collect::<Vec<_>>(), [u8::MAX, 0]);
+        // an empty color of the pasted canvas isn't added
+        let mut empty = CanvasImage::new(2, 2);
+        empty.raster_idx(PAINT);
+        image.add_canvas((0, 0), &empty);
+        assert_eq!(image.palette().count(), 2);
+        let mut image = CanvasImage::new(2, 2);
+        image.add_canvas((0, 0), &empty);
+        assert_eq!(image.palette().count(), 0);
+    }
+
+    #[test]
+    pub fn test_fill_contiguous() {
+        let mut canvas = canvas(&[
+            "......",
+            ".####.",
+            ".#..#.",
+            ".####.",
+        ]);
+        assert_eq!(fill(&mut canvas, (2, 2), FillOptions::default()), [
+            "......",
+            "......",
+            "..oo..",
+            "......",
+        ]);
+    }
+
+    #[test]
+    pub fn test_fill_tolerance() {
+        // the fill goes up to the anti-aliased edge, which only receives the spare presence
+        let mut image = canvas(&[
+            "..+#...",
+            "..+#...",
+            "..+#...",
+        ]);
+        assert_eq!(fill(&mut image, (0, 0), FillOptions::default()), [
+            "ooo....",
+            "ooo....",
+            "ooo....",
+        ]);
+        let paint = image.raster_idx(PAINT);
+        assert_eq!(image.colors[paint].0[(2, 0)], u8::MAX-128);
+        // from the ink, the fill only spreads through pixels without ink within the tolerance
+        let rows = [
+            "##.##",
+            "##.##",
+        ];
+        assert_eq!(fill(&mut canvas(&rows), (0, 0), FillOptions::default()), [
+            "oo...",
+            "oo...",
+        ]);
+        assert_eq!(fill(&mut canvas(&rows), (0, 0), FillOptions { tolerance: u8::MAX, ..Default::default() }), [
+            "oo.oo",
+            "oo.oo",
+        ]);
+    }
+
+    #[test]
+    pub fn test_fill_global() {
+        let mut canvas = canvas(&[
+            "..#...",
+            "..#.#.",
+            "..#...",
+        ]);
+        assert_eq!(fill(&mut canvas, (0, 0), FillOptions { global: true, ..Default::default() }), [
+            "oo.ooo",
+            "oo.o.o",
+            "oo.ooo",
+        ]);
+    }
+
+    #[test]
+    pub fn test_fill_diagonal() {
+        let rows = [
+            "...#",
+            "..#.",
+            ".#..",
+            "#...",
+        ];
+        assert_eq!(fill(&mut canvas(&rows), (0, 0), FillOptions::default()), [
+            "ooo.",
+            "oo..",
+            "o...",
+            "....",
+        ]);
+        assert_eq!(fill(&mut canvas(&rows), (0, 0), FillOptions { diagonal: true, ..Default::default() }), [
+            "ooo.",
+            "oo.o",
+            "o.oo",
+            ".ooo",
+        ]);
+    }
+
+    #[test]
+    pub fn test_fill_sample_current_color() {
+        let mut canvas = canvas(&[
+            "..#..",
+            "..#..",
+        ]);
+        // only the paint's presence is compared so the ink doesn't stop the fill,
+        // but the ink still covers its pixels entirely so there's no room for the paint there
+        assert_eq!(fill(&mut canvas, (0, 0), FillOptions { sample: FillSample::CurrentColor, ..Default::default() }), [
+            "oo.oo",
+            "oo.oo",
+        ]);
+    }
+
+    #[test]
+    pub fn test_fill_expand_under_edges() {
+        // the ink fades out under a green edge, then the green covers the pixels entirely
+        let edge = || {
+            let mut image = canvas(&[
+                "###+....",
+                "###+....",
+            ]);
+            let green = image.raster_idx(Rgba([0, u8::MAX, 0, u8::MAX]));
+            for y in 0..2 {
+                image.colors[green].0[(3, y)] = 127;
+                image.colors[green].0[(4, y)] = 128;
+                image.colors[green].0[(5, y)] = u8::MAX;
+            }
+            image
+        };
+        let mut image = edge();
+        assert_eq!(fill(&mut image, (0, 0), FillOptions::default()), [
+            "oooo....",
+            "oooo....",
+        ]);
+        let mut image = edge();
+        // the fill expands under partially covered pixels but is still stopped by fully covered ones
+        assert_eq!(fill(&mut image, (0, 0), FillOptions { expand_under_edges: 4, ..Default::default() }), [
+            "ooooo...",
+            "ooooo...",
+        ]);
+        let paint = image.raster_idx(PAINT);
+        assert_eq!(image.colors[paint].0[(4, 1)], u8::MAX-128);
+    }
+
+    #[test]
+    pub fn test_fill_gap_closing() {
+        let rows = [
+            "..........",
+            ".####.###.",
+            ".#......#.",
+            ".#......#.",
+            ".#......#.",
+            ".#......#.",
+            ".########.",
+            "..........",
+        ];
+        // the fill leaks through the gap
+        assert_eq!(fill(&mut canvas(&rows), (4, 3), FillOptions::default())[0], "oooooooooo");
+        assert_eq!(fill(&mut canvas(&rows), (4, 3), FillOptions { gap_closing_radius: 1, ..Default::default() }), [
+            "..........",
+            "..........",
+            "..oooooo..",
+            "..oooooo..",
+            "..oooooo..",
+            "..oooooo..",
+            "..........",
+            "..........",
+        ]);
+    }
+
+    #[test]
+    pub fn test_fill_gap_closing_boundary() {
+        let rows = [
+            "..........",
+            "..........",
+            ".###..###.",
+            ".#......#.",
+            ".#......#.",
+            ".#......#.",
+            ".########.",
+            "..........",
+        ];
+        // the gap is exactly 2 pixels wide
+        let radius = |gap_closing_radius| FillOptions { gap_closing_radius, ..Default::default() };
+        assert_eq!(fill(&mut canvas(&rows), (4, 4), radius(0))[0], "oooooooooo");
+        assert_eq!(fill(&mut canvas(&rows), (4, 4), radius(1))[0], "..........");
+        let rows = [
+            "..........",
+            "..........",
+            ".###...##.",
+            ".#......#.",
+            ".#......#.",
+            ".#......#.",
+            ".########.",
+            "..........",
+        ];
+        // 3 pixels are too wide for a radius of 1
+        assert_eq!(fill(&mut canvas(&rows), (4, 4), radius(1))[0], "oooooooooo");
+    }
+
+    #[test]
+    pub fn test_fill_wide_gap_closing() {
+        let rows = [
+            "...........",
+            "...........",
+            "...........",
+            ".###...###.",
+            ".#.......#.",
+            ".#.......#.",
+            ".#.......#.",
+            ".#.......#.",
+            ".#.......#.",
+            ".#########.",
+            "...........",
+        ];
+        // a 3 pixels gap takes a radius of 2
+        let radius = |gap_closing_radius| FillOptions { gap_closing_radius, ..Default::default() };
+        assert_eq!(fill(&mut canvas(&rows), (5, 6), radius(1))[0], "ooooooooooo");
+        let filled = fill(&mut canvas(&rows), (5, 6), radius(2));
+        assert_eq!(filled[0], "...........");
+        assert_eq!(filled[4], "..ooooooo..");
+        assert_eq!(filled[8], "..ooooooo..");
+    }
+
+    #[test]
+    pub fn test_preview_gradient() {
+        let mut canvas = canvas(&["##..", "##.."]);
+        let gradient = Gradient::new(GradientKind::Linear, PAINT, Rgba([0, 0, u8::MAX, u8::MAX]));
+        let (start, end) = (Vec2::new(0., 0.), Vec2::new(4., 0.));
+        canvas.preview_gradient(&gradient, start, end);
+        assert_eq!(canvas.palette().count(), 1);
+        assert_ne!(*canvas.render().get_pixel(3, 0), Rgba([0; 4]));
+        let render = canvas.clear_gradient_preview();
+        assert_eq!([*render.get_pixel(0, 0), *render.get_pixel(3, 0)], [INK, Rgba([0; 4])]);
+        canvas.apply_gradient(&gradient, start, end);
+        assert_eq!(canvas.palette().count(), 3);
+    }
+}
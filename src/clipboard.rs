@@ -0,0 +1,100 @@
+use std::{borrow::Cow, fs, path::PathBuf};
+
+use anyhow::{Context, Result};
+use arboard::{Clipboard, ImageData};
+use canvas::{document, CanvasImage};
+use image::RgbaImage;
+
+const PAYLOAD_MAGIC: &[u8; 8] = b"CNVSCLIP";
+
+/// What the clipboard holds, a drawing copied from the app or an image from anywhere else
+pub enum ClipboardContent {
+    Canvas(Box<CanvasImage>),
+    Image(RgbaImage),
+}
+
+impl ClipboardContent {
+    pub fn render(&self) -> Cow<'_, RgbaImage> {
+        match self {
+            Self::Canvas(canvas) => Cow::Owned(canvas.render()),
+            Self::Image(image) => Cow::Borrowed(image),
+        }
+    }
+
+    /// The content as a drawing of its own size
+    pub fn into_canvas(self) -> CanvasImage {
+        match self {
+            Self::Canvas(canvas) => *canvas,
+            Self::Image(image) => {
+                let mut canvas = CanvasImage::new(image.width() as usize, image.height() as usize);
+                canvas.add_image((0, 0), image.as_raw(), image.width() as usize);
+                canvas
+            },
+        }
+    }
+}
+
+/// Copies drawings as rgba images that other apps understand, along with their presences.
+/// arboard (as of 3.4) can't put custom formats on the clipboard, only text, html and images,
+/// so the presences go to a payload file shared by every window instead. The file is tagged with the size and a
+/// checksum of the rgba pixels, it is only used while the clipboard still holds that exact image
+pub struct CanvasClipboard {
+    clipboard: Clipboard,
+    payload_path: PathBuf,
+}
+
+impl CanvasClipboard {
+    pub fn new(payload_path: PathBuf) -> Result<Self> {
+        Ok(Self { clipboard: Clipboard::new()?, payload_path })
+    }
+
+    pub fn copy(&mut self, image: &CanvasImage) -> Result<()> {
+        let render = image.render();
+        let mut payload = PAYLOAD_MAGIC.to_vec();
+        payload.extend(image_tag(&render));
+        document::write_to(image, &mut payload)?;
+        if let Some(dir) = self.payload_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.payload_path, payload)
+            .with_context(|| format!("couldn't write {}", self.payload_path.display()))?;
+        self.clipboard.set_image(ImageData {
+            width: image.width(),
+            height: image.height(),
+            bytes: Cow::Borrowed(render.as_raw()),
+        })?;
+        Ok(())
+    }
+
+    /// The copied drawing if the clipboard image came from the app, else the clipboard image as is
+    pub fn paste(&mut self) -> Option<ClipboardContent> {
+        let img = self.clipboard.get_image().ok()?;
+        let image = RgbaImage::from_raw(img.width as u32, img.height as u32, img.bytes.into_owned())?;
+        match self.payload(&image) {
+            Some(canvas) => Some(ClipboardContent::Canvas(Box::new(canvas))),
+            None => Some(ClipboardContent::Image(image)),
+        }
+    }
+
+    fn payload(&self, image: &RgbaImage) -> Option<CanvasImage> {
+        let bytes = fs::read(&self.payload_path).ok()?;
+        let bytes = bytes.strip_prefix(PAYLOAD_MAGIC)?;
+        let (tag, document) = bytes.split_at_checked(16)?;
+        if *tag != image_tag(image) {
+            return None;
+        }
+        document::read_from(document).ok()
+            .filter(|canvas| [canvas.width(), canvas.height()] == [image.width() as usize, image.height() as usize])
+    }
+}
+
+/// The size and the FNV-1a checksum of the pixels, which unlike the std hashers is the same in every build
+fn image_tag(image: &RgbaImage) -> [u8; 16] {
+    let checksum = image.as_raw().iter()
+        .fold(0xcbf29ce484222325u64, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+    let mut tag = [0; 16];
+    tag[..4].copy_from_slice(&image.width().to_le_bytes());
+    tag[4..8].copy_from_slice(&image.height().to_le_bytes());
+    tag[8..].copy_from_slice(&checksum.to_le_bytes());
+    tag
+}
@@ -0,0 +1,202 @@
+use std::{fs::{self, File}, io::{BufReader, BufWriter, Read, Write}, path::Path};
+
+use anyhow::{bail, Context, Result};
+use grid::Grid;
+use image::Rgba;
+
+use crate::{canvas_image::CanvasImage, export::{self, ExportOptions}, packed_u8::PackedU8s, raster::Raster};
+
+/// Extension of the lossless documents, which keep every color raster
+pub const EXTENSION: &str = "canvas";
+const MAGIC: &[u8; 4] = b"CNVS";
+const VERSION: u8 = 1;
+/// Private png chunk holding the document, it isn't safe to copy so editors modifying the pixels drop it
+pub const PNG_CHUNK: [u8; 4] = *b"cnVS";
+const PNG_SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+/// Documents claiming more pixels are rejected before anything is allocated, 16384 x 16384
+const MAX_PIXELS: usize = 1 << 28;
+
+/// Writes the palette and the presence rasters, each raster is packed on as few bits as its values need
+pub fn write_to(image: &CanvasImage, mut writer: impl Write) -> Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[VERSION])?;
+    writer.write_all(&(image.width() as u32).to_le_bytes())?;
+    writer.write_all(&(image.height() as u32).to_le_bytes())?;
+    writer.write_all(&(image.palette().count() as u32).to_le_bytes())?;
+    for (color, raster) in image.palette() {
+        let packed = PackedU8s::from(raster.0.flatten());
+        writer.write_all(&color.0)?;
+        writer.write_all(&[packed.bits()])?;
+        writer.write_all(&(packed.raw().len() as u32).to_le_bytes())?;
+        writer.write_all(packed.raw())?;
+    }
+    Ok(())
+}
+
+pub fn read_from(mut reader: impl Read) -> Result<CanvasImage> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("not a canvas document");
+    }
+    let version = read_u8(&mut reader)?;
+    if version != VERSION {
+        bail!("unsupported canvas document version {version}");
+    }
+    let width = read_u32(&mut reader)? as usize;
+    let height = read_u32(&mut reader)? as usize;
+    let pixels = width.checked_mul(height)
+        .filter(|&pixels| pixels <= MAX_PIXELS)
+        .with_context(|| format!("a {width}x{height} document is too large"))?;
+    let color_count = read_u32(&mut reader)?;
+    let mut colors = Vec::new();
+    for _ in 0..color_count {
+        let mut rgba = [0; 4];
+        reader.read_exact(&mut rgba)?;
+        let bits = read_u8(&mut reader)?;
+        // the length is checked before allocating, so a corrupted file can't claim gigabytes
+        let length = read_u32(&mut reader)? as usize;
+        if PackedU8s::raw_len(bits, pixels) != Some(length) {
+            bail!("corrupted color raster");
+        }
+        let mut data = vec![0; length];
+        reader.read_exact(&mut data)?;
+        let packed = PackedU8s::from_raw(bits, data, pixels)
+            .context("corrupted color raster")?;
+        let values = packed.iter().take(pixels).collect();
+        colors.push((Rgba(rgba), Raster(Grid::from_vec(values, height))));
+    }
+    CanvasImage::from_palette(width, height, colors)
+}
+
+fn read_u8(reader: &mut impl Read) -> Result<u8> {
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn is_document(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == EXTENSION)
+}
+
+/// The document embedded in a png, if any
+fn embedded_document(bytes: &[u8]) -> Option<&[u8]> {
+    let mut chunks = bytes.strip_prefix(PNG_SIGNATURE)?;
+    // each chunk is its length, type, data and crc
+    while chunks.len() >= 12 {
+        let length = u32::from_be_bytes(chunks[..4].try_into().unwrap()) as usize;
+        let data = chunks.get(8..8+length)?;
+        if chunks[4..8] == PNG_CHUNK {
+            return Some(data);
+        }
+        chunks = chunks.get(12+length..)?;
+    }
+    None
+}
+
+/// Decodes an image, preferring the document embedded in pngs when it matches the image size
+pub fn read_image(bytes: &[u8]) -> Result<CanvasImage> {
+    let img = image::load_from_memory(bytes)?.to_rgba8();
+    let embedded = embedded_document(bytes).and_then(|document| read_from(document).ok());
+    if let Some(image) = embedded.filter(|image| [image.width(), image.height()] == [img.width() as usize, img.height() as usize]) {
+        return Ok(image);
+    }
+    let mut res = CanvasImage::new(img.width() as usize, img.height() as usize);
+    res.add_image((0, 0), img.as_raw(), img.width() as usize);
+    Ok(res)
+}
+
+/// Opens a canvas document, or any supported image format
+pub fn open(path: &Path) -> Result<CanvasImage> {
+    if is_document(path) {
+        return read_from(BufReader::new(File::open(path)?));
+    }
+    let bytes = fs::read(path).with_context(|| format!("couldn't open {}", path.display()))?;
+    read_image(&bytes).with_context(|| format!("couldn't open {}", path.display()))
+}
+
+/// Saves as a canvas document, or as an image in the format matching the extension, pngs embed the document
+pub fn save(image: &CanvasImage, path: &Path) -> Result<()> {
+    if is_document(path) {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write_to(image, &mut writer)?;
+        writer.flush()?;
+        return Ok(());
+    }
+    export::export(image, path, &ExportOptions { embed_document: true, ..Default::default() })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use grid::Grid;
+    use image::Rgba;
+    use crate::{canvas_image::CanvasImage, export::{self, ExportFormat, ExportOptions}, raster::Raster};
+    use super::{read_from, read_image, write_to};
+
+    #[test]
+    pub fn test_roundtrip() {
+        let mut pixels = Vec::new();
+        for i in 0..(7*5) {
+            pixels.extend_from_slice(if i % 3 == 0 { &[255, 0, 0, 255] } else { &[0, 0, 255, 255] });
+        }
+        let mut image = CanvasImage::new(7, 5);
+        image.add_image((0, 0), &pixels, 7);
+        let mut bytes = Vec::new();
+        write_to(&image, &mut bytes).unwrap();
+        let read = read_from(bytes.as_slice()).unwrap();
+        assert_eq!(read.width(), 7);
+        assert_eq!(read.height(), 5);
+        assert_eq!(read.render(), image.render());
+        assert_eq!(
+            read.palette().map(|(color, _)| color).collect::<Vec<_>>(),
+            [Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 255])]
+        );
+        assert!(read_from(&bytes[..bytes.len()-1]).is_err());
+    }
+
+    #[test]
+    pub fn test_corrupt_lengths() {
+        let mut image = CanvasImage::new(7, 5);
+        image.add_image((0, 0), &[255, 0, 0, 255].repeat(7*5), 7);
+        let mut bytes = Vec::new();
+        write_to(&image, &mut bytes).unwrap();
+        // the raster length follows the magic, version, dims, color count, color and bits
+        let mut corrupt = bytes.clone();
+        corrupt[22..26].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(read_from(corrupt.as_slice()).is_err());
+        let mut corrupt = bytes.clone();
+        corrupt[5..13].copy_from_slice(&[u8::MAX; 8]);
+        assert!(read_from(corrupt.as_slice()).is_err());
+        // a 0 pixel wide document can't fit the rasters
+        let mut corrupt = bytes;
+        corrupt[5..9].copy_from_slice(&0u32.to_le_bytes());
+        assert!(read_from(corrupt.as_slice()).is_err());
+        assert!(CanvasImage::from_palette(3, 3, vec![(Rgba([0; 4]), Raster(Grid::new(2, 3)))]).is_err());
+    }
+
+    #[test]
+    pub fn test_png_embedding() {
+        let mut image = CanvasImage::new(3, 2);
+        // a half present color renders the same as a semi transparent one, only the document tells them apart
+        image.add_image((0, 0), &[255, 0, 0, 255].repeat(6), 3);
+        image.swap_color(Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 128]));
+        let mut bytes = Cursor::new(Vec::new());
+        let options = ExportOptions { embed_document: true, ..Default::default() };
+        export::write_to(&image.render(), Some(&image), ExportFormat::Png, &options, &mut bytes).unwrap();
+        let read = read_image(bytes.get_ref()).unwrap();
+        assert_eq!(read.palette().map(|(color, _)| color).collect::<Vec<_>>(), [Rgba([0, 0, 255, 128])]);
+
+        let mut bytes = Cursor::new(Vec::new());
+        export::write_to(&image.render(), Some(&image), ExportFormat::Png, &ExportOptions::default(), &mut bytes).unwrap();
+        let read = read_image(bytes.get_ref()).unwrap();
+        assert_eq!(read.palette().count(), 1);
+        assert_ne!(read.palette().next().unwrap().0, Rgba([0, 0, 255, 128]));
+    }
+}
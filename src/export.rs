@@ -0,0 +1,297 @@
+use std::{borrow::Cow, fs, io::{Cursor, Seek, Write}, path::Path};
+
+use anyhow::{bail, Context, Result};
+use image::{
+    codecs::{bmp::BmpEncoder, jpeg::JpegEncoder, qoi::QoiEncoder, tiff::TiffEncoder, webp::WebPEncoder},
+    DynamicImage, Rgba, RgbaImage
+};
+
+use crate::{canvas_image::CanvasImage, document, quantize::{self, Dithering}};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExportFormat {
+    Png,
+    Jpeg,
+    WebP,
+    Bmp,
+    Tiff,
+    Qoi,
+}
+
+impl ExportFormat {
+    pub const ALL: [Self; 6] = [Self::Png, Self::Jpeg, Self::WebP, Self::Bmp, Self::Tiff, Self::Qoi];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Png => "PNG",
+            Self::Jpeg => "JPEG",
+            Self::WebP => "WebP",
+            Self::Bmp => "BMP",
+            Self::Tiff => "TIFF",
+            Self::Qoi => "QOI",
+        }
+    }
+
+    /// The extensions of the format, the first one being the preferred one
+    pub fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            Self::Png => &["png"],
+            Self::Jpeg => &["jpg", "jpeg"],
+            Self::WebP => &["webp"],
+            Self::Bmp => &["bmp"],
+            Self::Tiff => &["tiff", "tif"],
+            Self::Qoi => &["qoi"],
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or_default().to_lowercase();
+        match Self::ALL.into_iter().find(|format| format.extensions().contains(&ext.as_str())) {
+            Some(format) => Ok(format),
+            None => {
+                let supported = Self::ALL.map(|format| format.extensions()[0]).join(", ");
+                bail!("unsupported image format \"{ext}\", expected one of {supported}")
+            },
+        }
+    }
+
+    /// Formats without an alpha channel are always flattened
+    pub fn supports_alpha(&self) -> bool {
+        *self != Self::Jpeg
+    }
+
+    /// Whether the format is lossy and uses `ExportOptions::quality`
+    pub fn has_quality(&self) -> bool {
+        *self == Self::Jpeg
+    }
+
+    /// Whether the format can store the pixels as indices in a palette, see `ExportOptions::indexed`
+    pub fn supports_indexed(&self) -> bool {
+        *self == Self::Png
+    }
+}
+
+#[derive(Clone)]
+pub struct ExportOptions {
+    /// From 1 (smallest) to 100 (best), for lossy formats
+    pub quality: u8,
+    /// Composites the drawing over the background, which also happens for formats without alpha
+    pub flatten: bool,
+    pub background: Rgba<u8>,
+    /// Writes at most 256 colors, drawings with more colors are quantized to their palette
+    pub indexed: bool,
+    pub dithering: Dithering,
+    /// Embeds the document in pngs, so they can be reopened without losing the presences
+    pub embed_document: bool,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            quality: 90,
+            flatten: false,
+            background: Rgba([u8::MAX; 4]),
+            indexed: false,
+            dithering: Dithering::default(),
+            embed_document: false,
+        }
+    }
+}
+
+/// The render composited over a background color
+pub fn flattened(render: &RgbaImage, background: Rgba<u8>) -> RgbaImage {
+    let mut res = render.clone();
+    for pixel in res.pixels_mut() {
+        *pixel = flattened_color(*pixel, background);
+    }
+    res
+}
+
+fn flattened_color(color: Rgba<u8>, background: Rgba<u8>) -> Rgba<u8> {
+    let alpha = color[3] as f32/u8::MAX as f32;
+    let background_alpha = background[3] as f32/u8::MAX as f32*(1.-alpha);
+    let out_alpha = alpha + background_alpha;
+    if out_alpha == 0. {
+        return color;
+    }
+    let mut res = color;
+    for c in 0..3 {
+        res[c] = ((color[c] as f32*alpha + background[c] as f32*background_alpha)/out_alpha).round() as u8;
+    }
+    res[3] = (out_alpha*u8::MAX as f32).round() as u8;
+    res
+}
+
+/// Writes the png with the png crate, which can add the document chunk. Indexed pngs have 8 bits per index
+/// and a transparency chunk if any color isn't opaque
+fn write_png(render: &RgbaImage, source: Option<&CanvasImage>, options: &ExportOptions, writer: impl Write) -> Result<()> {
+    let mut encoder = png::Encoder::new(writer, render.width(), render.height());
+    encoder.set_depth(png::BitDepth::Eight);
+    let data = if options.indexed {
+        // the alpha of a palette color is the opacity of its strokes, a fully present color renders opaque
+        // (which flattening leaves as is), the transparency is added by the quantization if the render needs it
+        let mut palette = Vec::new();
+        for (color, _) in source.into_iter().flat_map(|image| image.palette()) {
+            let opaque = Rgba([color[0], color[1], color[2], u8::MAX]);
+            if !palette.contains(&opaque) {
+                palette.push(opaque);
+            }
+        }
+        let (colors, indices) = quantize::indexed(render, &palette, options.dithering);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_palette(colors.iter().flat_map(|color| [color[0], color[1], color[2]]).collect::<Vec<_>>());
+        if colors.iter().any(|color| color[3] < u8::MAX) {
+            encoder.set_trns(colors.iter().map(|color| color[3]).collect::<Vec<_>>());
+        }
+        Cow::Owned(indices)
+    } else {
+        encoder.set_color(png::ColorType::Rgba);
+        Cow::Borrowed(render.as_raw())
+    };
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    let same_size = |image: &&CanvasImage| [image.width(), image.height()] == [render.width() as usize, render.height() as usize];
+    if let Some(image) = source.filter(same_size).filter(|_| options.embed_document) {
+        let mut bytes = Vec::new();
+        document::write_to(image, &mut bytes)?;
+        writer.write_chunk(png::chunk::ChunkType(document::PNG_CHUNK), &bytes)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Encodes the render, indexed renders are quantized to the palette of the source,
+/// which is also the document embedded in pngs
+pub fn write_to(
+    render: &RgbaImage, source: Option<&CanvasImage>, format: ExportFormat, options: &ExportOptions, mut writer: impl Write + Seek
+) -> Result<()> {
+    let render = if options.flatten || !format.supports_alpha() {
+        flattened(render, options.background)
+    } else {
+        render.clone()
+    };
+    let res = match format {
+        ExportFormat::Png => return write_png(&render, source, options, writer),
+        ExportFormat::Jpeg => DynamicImage::ImageRgba8(render).to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(writer, options.quality.clamp(1, 100))),
+        ExportFormat::WebP => render.write_with_encoder(WebPEncoder::new_lossless(writer)),
+        ExportFormat::Bmp => render.write_with_encoder(BmpEncoder::new(&mut writer)),
+        ExportFormat::Tiff => render.write_with_encoder(TiffEncoder::new(writer)),
+        ExportFormat::Qoi => render.write_with_encoder(QoiEncoder::new(writer)),
+    };
+    res.with_context(|| format!("couldn't encode the image as {}", format.name()))
+}
+
+/// Exports the render in the format matching the extension of the path
+pub fn export(image: &CanvasImage, path: &Path, options: &ExportOptions) -> Result<()> {
+    save_render(&image.render(), Some(image), path, options)
+}
+
+/// The image is fully encoded before the file is touched, then written next to it and renamed over it,
+/// so a failed export leaves the previous file as it was
+pub fn save_render(render: &RgbaImage, source: Option<&CanvasImage>, path: &Path, options: &ExportOptions) -> Result<()> {
+    let format = ExportFormat::from_path(path)?;
+    let mut bytes = Cursor::new(Vec::new());
+    write_to(render, source, format, options, &mut bytes)?;
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let res = fs::write(&partial, bytes.get_ref()).and_then(|_| fs::rename(&partial, path));
+    if res.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    res.with_context(|| format!("couldn't write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, fs, io::Cursor, path::Path, process};
+    use grid::Grid;
+    use image::{ImageFormat, Rgba, RgbaImage};
+    use crate::{canvas_image::CanvasImage, quantize::Dithering, raster::Raster};
+    use super::{save_render, write_to, ExportFormat, ExportOptions};
+
+    #[test]
+    pub fn test_formats() {
+        let mut render = RgbaImage::new(4, 3);
+        render.put_pixel(1, 1, Rgba([200, 0, 0, u8::MAX]));
+        render.put_pixel(2, 1, Rgba([0, 0, 200, 128]));
+        let options = ExportOptions { background: Rgba([0, u8::MAX, 0, u8::MAX]), ..Default::default() };
+        for format in ExportFormat::ALL {
+            let mut bytes = Cursor::new(Vec::new());
+            write_to(&render, None, format, &options, &mut bytes).unwrap();
+            let decoded = image::load_from_memory(bytes.get_ref()).unwrap().to_rgba8();
+            assert_eq!(decoded.dimensions(), (4, 3), "{format:?}");
+            if format.has_quality() {
+                continue;
+            }
+            assert_eq!(decoded, render, "{format:?}");
+        }
+        // jpeg has no alpha, transparent pixels get the background
+        let mut bytes = Cursor::new(Vec::new());
+        write_to(&render, None, ExportFormat::Jpeg, &options, &mut bytes).unwrap();
+        let decoded = image::load_from_memory_with_format(bytes.get_ref(), ImageFormat::Jpeg).unwrap().to_rgba8();
+        let corner = decoded.get_pixel(0, 0);
+        assert!(corner[0] < 30 && corner[1] > 225 && corner[2] < 30, "{corner:?}");
+    }
+
+    #[test]
+    pub fn test_indexed_png_palette_alpha() {
+        // half opaque red strokes, fully present on the right, mixed with blue and green elsewhere
+        let (width, height) = (256, 4);
+        let (mut red, mut green, mut blue) = (Grid::new(width, height), Grid::new(width, height), Grid::new(width, height));
+        for ((x, y), presence) in red.indexed_iter_mut() {
+            *presence = x as u8;
+            green[(x, y)] = ((y*20) as u8).min(u8::MAX - x as u8);
+            blue[(x, y)] = u8::MAX - *presence - green[(x, y)];
+        }
+        let image = CanvasImage::from_palette(width, height, vec![
+            (Rgba([200, 0, 0, 128]), Raster(red)),
+            (Rgba([0, 200, 0, u8::MAX]), Raster(green)),
+            (Rgba([0, 0, 200, u8::MAX]), Raster(blue)),
+        ]).unwrap();
+        let render = image.render();
+        // more colors than an indexed png can hold, so the render is quantized to the palette
+        assert!(render.pixels().collect::<HashSet<_>>().len() > 256);
+        let options = ExportOptions { indexed: true, dithering: Dithering::None, ..Default::default() };
+        let mut bytes = Cursor::new(Vec::new());
+        write_to(&render, Some(&image), ExportFormat::Png, &options, &mut bytes).unwrap();
+        let decoded = image::load_from_memory(bytes.get_ref()).unwrap().to_rgba8();
+        assert_eq!(*decoded.get_pixel(255, 0), Rgba([200, 0, 0, u8::MAX]));
+        assert!(decoded.pixels().all(|pixel| pixel[3] == u8::MAX));
+    }
+
+    #[test]
+    pub fn test_failed_export_keeps_file() {
+        let path = std::env::temp_dir().join(format!("canvas-export-test-{}.jpg", process::id()));
+        fs::write(&path, b"previous export").unwrap();
+        // too wide for jpeg
+        assert!(save_render(&RgbaImage::new(65536, 1), None, &path, &ExportOptions::default()).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"previous export");
+        save_render(&RgbaImage::new(4, 3), None, &path, &ExportOptions::default()).unwrap();
+        assert_eq!(image::open(&path).unwrap().to_rgba8().dimensions(), (4, 3));
+        assert!(!path.with_extension("jpg.partial").exists());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    pub fn test_indexed_png() {
+        let mut render = RgbaImage::new(5, 4);
+        render.put_pixel(1, 1, Rgba([200, 0, 0, u8::MAX]));
+        render.put_pixel(2, 3, Rgba([0, 0, 200, 128]));
+        let options = ExportOptions { indexed: true, ..Default::default() };
+        let mut bytes = Cursor::new(Vec::new());
+        write_to(&render, None, ExportFormat::Png, &options, &mut bytes).unwrap();
+        // the color type of the header is indexed
+        assert_eq!(bytes.get_ref()[25], 3);
+        let decoded = image::load_from_memory(bytes.get_ref()).unwrap().to_rgba8();
+        assert_eq!(decoded, render);
+    }
+
+    #[test]
+    pub fn test_from_path() {
+        assert_eq!(ExportFormat::from_path(Path::new("a/b.JPEG")).unwrap(), ExportFormat::Jpeg);
+        assert_eq!(ExportFormat::from_path(Path::new("b.tif")).unwrap(), ExportFormat::Tiff);
+        let err = ExportFormat::from_path(Path::new("b.xcf")).unwrap_err().to_string();
+        assert!(err.contains("\"xcf\"") && err.contains("qoi"), "{err}");
+    }
+}
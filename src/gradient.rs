@@ -0,0 +1,87 @@
+use std::f32::consts::TAU;
+
+use glam::Vec2;
+use image::Rgba;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GradientKind {
+    Linear,
+    Radial,
+    Angular,
+}
+
+pub struct Gradient {
+    pub kind: GradientKind,
+    /// (position in [0, 1], color), sorted by position
+    pub stops: Vec<(f32, Rgba<u8>)>,
+}
+
+impl Gradient {
+    pub fn new(kind: GradientKind, start_color: Rgba<u8>, end_color: Rgba<u8>) -> Self {
+        Self { kind, stops: vec![(0., start_color), (1., end_color)] }
+    }
+
+    pub fn sort_stops(&mut self) {
+        self.stops.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+    }
+
+    /// Position along the gradient in [0, 1] of a point, for a gradient dragged from start to end
+    pub fn t_at(&self, start: Vec2, end: Vec2, pos: Vec2) -> f32 {
+        let dir = end-start;
+        let length_sq = dir.length_squared();
+        if length_sq == 0. {
+            return 0.;
+        }
+        let diff = pos-start;
+        match self.kind {
+            GradientKind::Linear => (diff.dot(dir)/length_sq).clamp(0., 1.),
+            GradientKind::Radial => (diff.length_squared()/length_sq).sqrt().min(1.),
+            GradientKind::Angular => (diff.to_angle()-dir.to_angle()).rem_euclid(TAU)/TAU,
+        }
+    }
+
+    /// The 2 stops surrounding t with their weights, which sum to 1
+    pub fn weights(&self, t: f32) -> [(usize, f32); 2] {
+        let last = self.stops.len()-1;
+        if t <= self.stops[0].0 {
+            return [(0, 1.), (0, 0.)];
+        }
+        if t >= self.stops[last].0 {
+            return [(last, 1.), (last, 0.)];
+        }
+        let i = self.stops.iter().rposition(|(pos, _)| *pos <= t).unwrap();
+        let (start, end) = (self.stops[i].0, self.stops[i+1].0);
+        let f = if end > start { (t-start)/(end-start) } else { 0. };
+        [(i, 1.-f), (i+1, f)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec2;
+    use image::Rgba;
+    use super::{Gradient, GradientKind};
+
+    #[test]
+    pub fn test_linear_weights() {
+        let mut gradient = Gradient::new(GradientKind::Linear, Rgba([0, 0, 0, u8::MAX]), Rgba([u8::MAX; 4]));
+        gradient.stops.push((0.5, Rgba([u8::MAX, 0, 0, u8::MAX])));
+        gradient.sort_stops();
+        let (start, end) = (Vec2::new(0., 0.), Vec2::new(10., 0.));
+        assert_eq!(gradient.weights(gradient.t_at(start, end, Vec2::new(-5., 3.))), [(0, 1.), (0, 0.)]);
+        assert_eq!(gradient.weights(gradient.t_at(start, end, Vec2::new(2.5, 3.))), [(0, 0.5), (1, 0.5)]);
+        assert_eq!(gradient.weights(gradient.t_at(start, end, Vec2::new(10., 0.))), [(2, 1.), (2, 0.)]);
+    }
+
+    #[test]
+    pub fn test_radial_weights() {
+        let gradient = Gradient::new(GradientKind::Radial, Rgba([0, 0, 0, u8::MAX]), Rgba([u8::MAX; 4]));
+        let (start, end) = (Vec2::new(5., 5.), Vec2::new(9., 5.));
+        assert_eq!(gradient.weights(gradient.t_at(start, end, start)), [(0, 1.), (0, 0.)]);
+        // the distance to the start matters, not the direction
+        assert_eq!(gradient.weights(gradient.t_at(start, end, Vec2::new(5., 3.))), [(0, 0.5), (1, 0.5)]);
+        assert_eq!(gradient.weights(gradient.t_at(start, end, Vec2::new(3., 5.))), [(0, 0.5), (1, 0.5)]);
+        assert_eq!(gradient.weights(gradient.t_at(start, end, Vec2::new(5., 12.))), [(1, 1.), (1, 0.)]);
+    }
+}
@@ -0,0 +1,360 @@
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+use anyhow::{bail, Context, Result};
+use eframe::egui::{Key, KeyboardShortcut, Modifiers};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Action {
+    New,
+    Open,
+    Save,
+    SaveAs,
+    Export,
+    Copy,
+    Paste,
+    PasteAsNew,
+    Undo,
+    Redo,
+    SelectionTool,
+    FillTool,
+    BrushTool,
+    EraserTool,
+    LineTool,
+    RectangleTool,
+    EllipseTool,
+    PolygonTool,
+    GradientTool,
+    BrushSizeUp,
+    BrushSizeDown,
+    ZoomIn,
+    ZoomOut,
+    FitToWindow,
+    ActualPixels,
+    RotateLeft,
+    RotateRight,
+    ResetRotation,
+    FlipView,
+    TogglePixelGrid,
+    SwapColors,
+}
+
+impl Action {
+    pub const ALL: [Self; 31] = [
+        Self::New, Self::Open, Self::Save, Self::SaveAs, Self::Export, Self::Copy, Self::Paste, Self::PasteAsNew,
+        Self::Undo, Self::Redo, Self::SelectionTool, Self::FillTool, Self::BrushTool, Self::EraserTool, Self::LineTool,
+        Self::RectangleTool, Self::EllipseTool, Self::PolygonTool, Self::GradientTool, Self::BrushSizeUp,
+        Self::BrushSizeDown, Self::ZoomIn, Self::ZoomOut, Self::FitToWindow, Self::ActualPixels,
+        Self::RotateLeft, Self::RotateRight, Self::ResetRotation, Self::FlipView, Self::TogglePixelGrid,
+        Self::SwapColors,
+    ];
+
+    /// The key of the action in the config file
+    pub fn id(&self) -> &'static str {
+        match self {
+            Self::New => "new",
+            Self::Open => "open",
+            Self::Save => "save",
+            Self::SaveAs => "save_as",
+            Self::Export => "export",
+            Self::Copy => "copy",
+            Self::Paste => "paste",
+            Self::PasteAsNew => "paste_as_new",
+            Self::Undo => "undo",
+            Self::Redo => "redo",
+            Self::SelectionTool => "selection_tool",
+            Self::FillTool => "fill_tool",
+            Self::BrushTool => "brush_tool",
+            Self::EraserTool => "eraser_tool",
+            Self::LineTool => "line_tool",
+            Self::RectangleTool => "rectangle_tool",
+            Self::EllipseTool => "ellipse_tool",
+            Self::PolygonTool => "polygon_tool",
+            Self::GradientTool => "gradient_tool",
+            Self::BrushSizeUp => "brush_size_up",
+            Self::BrushSizeDown => "brush_size_down",
+            Self::ZoomIn => "zoom_in",
+            Self::ZoomOut => "zoom_out",
+            Self::FitToWindow => "fit_to_window",
+            Self::ActualPixels => "actual_pixels",
+            Self::RotateLeft => "rotate_left",
+            Self::RotateRight => "rotate_right",
+            Self::ResetRotation => "reset_rotation",
+            Self::FlipView => "flip_view",
+            Self::TogglePixelGrid => "toggle_pixel_grid",
+            Self::SwapColors => "swap_colors",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::New => "New",
+            Self::Open => "Open",
+            Self::Save => "Save",
+            Self::SaveAs => "Save As",
+            Self::Export => "Export",
+            Self::Copy => "Copy",
+            Self::Paste => "Paste",
+            Self::PasteAsNew => "Paste as New Document",
+            Self::Undo => "Undo",
+            Self::Redo => "Redo",
+            Self::SelectionTool => "Selection",
+            Self::FillTool => "Fill",
+            Self::BrushTool => "Brush",
+            Self::EraserTool => "Eraser",
+            Self::LineTool => "Line",
+            Self::RectangleTool => "Rectangle",
+            Self::EllipseTool => "Ellipse",
+            Self::PolygonTool => "Polygon",
+            Self::GradientTool => "Gradient",
+            Self::BrushSizeUp => "Brush Size Up",
+            Self::BrushSizeDown => "Brush Size Down",
+            Self::ZoomIn => "Zoom In",
+            Self::ZoomOut => "Zoom Out",
+            Self::FitToWindow => "Fit to Window",
+            Self::ActualPixels => "Actual Pixels",
+            Self::RotateLeft => "Rotate View Left",
+            Self::RotateRight => "Rotate View Right",
+            Self::ResetRotation => "Reset View Rotation",
+            Self::FlipView => "Flip View Horizontally",
+            Self::TogglePixelGrid => "Toggle Pixel Grid",
+            Self::SwapColors => "Swap Colors",
+        }
+    }
+
+    /// Whether holding the keys repeats the action
+    pub fn repeats(&self) -> bool {
+        matches!(self, Self::BrushSizeUp | Self::BrushSizeDown | Self::ZoomIn | Self::ZoomOut
+            | Self::RotateLeft | Self::RotateRight)
+    }
+
+    pub fn default_shortcut(&self) -> KeyboardShortcut {
+        let command = |key| KeyboardShortcut::new(Modifiers::COMMAND, key);
+        let command_shift = |key| KeyboardShortcut::new(Modifiers::COMMAND.plus(Modifiers::SHIFT), key);
+        let key = |key| KeyboardShortcut::new(Modifiers::NONE, key);
+        match self {
+            Self::New => command(Key::N),
+            Self::Open => command(Key::O),
+            Self::Save => command(Key::S),
+            Self::SaveAs => command_shift(Key::S),
+            Self::Export => command(Key::E),
+            Self::Copy => command(Key::C),
+            Self::Paste => command(Key::V),
+            Self::PasteAsNew => command_shift(Key::V),
+            Self::Undo => command(Key::Z),
+            Self::Redo => command_shift(Key::Z),
+            Self::SelectionTool => key(Key::S),
+            Self::FillTool => key(Key::F),
+            Self::BrushTool => key(Key::B),
+            Self::EraserTool => key(Key::E),
+            Self::LineTool => key(Key::L),
+            Self::RectangleTool => key(Key::R),
+            Self::EllipseTool => key(Key::O),
+            Self::PolygonTool => key(Key::P),
+            Self::GradientTool => key(Key::G),
+            Self::BrushSizeUp => key(Key::CloseBracket),
+            Self::BrushSizeDown => key(Key::OpenBracket),
+            Self::ZoomIn => command(Key::Equals),
+            Self::ZoomOut => command(Key::Minus),
+            Self::FitToWindow => command(Key::Num0),
+            Self::ActualPixels => command(Key::Num1),
+            Self::RotateLeft => command(Key::OpenBracket),
+            Self::RotateRight => command(Key::CloseBracket),
+            Self::ResetRotation => command(Key::R),
+            Self::FlipView => key(Key::M),
+            Self::TogglePixelGrid => command(Key::Quote),
+            Self::SwapColors => key(Key::X),
+        }
+    }
+}
+
+/// The key chord of every action, loaded from a config file where each action id maps to a chord
+/// such as "Ctrl+Shift+S", or to null to leave it unbound. Actions missing from the file keep their default
+pub struct Keymap {
+    path: PathBuf,
+    bindings: BTreeMap<Action, Option<KeyboardShortcut>>,
+}
+
+impl Keymap {
+    pub fn defaults(path: PathBuf) -> Self {
+        let bindings = Action::ALL.into_iter().map(|action| (action, Some(action.default_shortcut()))).collect();
+        Self { path, bindings }
+    }
+
+    /// Loads the config file if there is one, invalid entries are reported and keep their default
+    pub fn load(path: PathBuf) -> Self {
+        let mut keymap = Self::defaults(path);
+        let Ok(text) = fs::read_to_string(&keymap.path) else {
+            return keymap;
+        };
+        let config: BTreeMap<String, Option<String>> = match serde_json::from_str(&text) {
+            Ok(config) => config,
+            Err(err) => {
+                println!("Couldn't read the keymap {}, reason: {:?}", keymap.path.display(), err);
+                return keymap;
+            },
+        };
+        for (id, chord) in config {
+            let Some(action) = Action::ALL.into_iter().find(|action| action.id() == id) else {
+                println!("Unknown action \"{id}\" in the keymap");
+                continue;
+            };
+            match chord.as_deref().map(parse_chord).transpose() {
+                Ok(shortcut) => keymap.set(action, shortcut),
+                Err(err) => println!("Couldn't bind \"{id}\", reason: {:?}", err),
+            }
+        }
+        for (a, b) in keymap.conflicts() {
+            println!("\"{}\" and \"{}\" have the same shortcut, only the first one will be used", a.id(), b.id());
+        }
+        keymap
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let config = self.bindings.iter()
+            .map(|(action, shortcut)| (action.id(), shortcut.as_ref().map(format_chord)))
+            .collect::<BTreeMap<_, _>>();
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(&config)?)
+            .with_context(|| format!("couldn't write {}", self.path.display()))
+    }
+
+    pub fn shortcut(&self, action: Action) -> Option<KeyboardShortcut> {
+        self.bindings.get(&action).copied().flatten()
+    }
+
+    pub fn set(&mut self, action: Action, shortcut: Option<KeyboardShortcut>) {
+        self.bindings.insert(action, shortcut);
+    }
+
+    /// The first action bound to exactly this chord
+    pub fn action(&self, key: Key, modifiers: Modifiers) -> Option<Action> {
+        Action::ALL.into_iter().find(|&action| {
+            self.shortcut(action)
+                .is_some_and(|shortcut| shortcut.logical_key == key && modifiers.matches_exact(shortcut.modifiers))
+        })
+    }
+
+    /// The other actions bound to the same chord as `action`
+    pub fn conflicts_with(&self, action: Action) -> Vec<Action> {
+        let Some(shortcut) = self.shortcut(action) else {
+            return Vec::new();
+        };
+        Action::ALL.into_iter()
+            .filter(|&other| other != action && self.shortcut(other) == Some(shortcut))
+            .collect()
+    }
+
+    /// Every pair of actions sharing a chord
+    pub fn conflicts(&self) -> Vec<(Action, Action)> {
+        Action::ALL.into_iter()
+            .flat_map(|action| {
+                self.conflicts_with(action).into_iter().filter(move |&other| action < other).map(move |other| (action, other))
+            })
+            .collect()
+    }
+}
+
+/// The chord of a key pressed with modifiers, Ctrl and Cmd both become the command modifier
+pub fn pressed_chord(key: Key, modifiers: Modifiers) -> KeyboardShortcut {
+    let mut res = Modifiers { alt: modifiers.alt, shift: modifiers.shift, ..Modifiers::NONE };
+    if modifiers.command || modifiers.ctrl || modifiers.mac_cmd {
+        res = res.plus(Modifiers::COMMAND);
+    }
+    KeyboardShortcut::new(res, key)
+}
+
+/// Chords are written as modifiers followed by the key name, joined by "+", "Ctrl" being Cmd on macOS
+pub fn format_chord(shortcut: &KeyboardShortcut) -> String {
+    let modifiers = shortcut.modifiers;
+    let mut parts = Vec::new();
+    if modifiers.command || modifiers.ctrl || modifiers.mac_cmd {
+        parts.push("Ctrl");
+    }
+    if modifiers.alt {
+        parts.push("Alt");
+    }
+    if modifiers.shift {
+        parts.push("Shift");
+    }
+    parts.push(shortcut.logical_key.name());
+    parts.join("+")
+}
+
+pub fn parse_chord(chord: &str) -> Result<KeyboardShortcut> {
+    let chord = chord.trim();
+    if chord.is_empty() {
+        bail!("empty shortcut");
+    }
+    // the key after the last "+", which can be "+" itself as in "Ctrl++"
+    let (modifier_names, key_name) = match chord.strip_suffix("++") {
+        Some(modifier_names) => (modifier_names, "+"),
+        None if chord == "+" => ("", "+"),
+        None => chord.rsplit_once('+').unwrap_or(("", chord)),
+    };
+    let mut modifiers = Modifiers::NONE;
+    let modifier_names = if modifier_names.is_empty() { Vec::new() } else { modifier_names.split('+').collect() };
+    for part in modifier_names.into_iter().map(str::trim) {
+        modifiers = modifiers.plus(match part.to_lowercase().as_str() {
+            "ctrl" | "cmd" | "command" => Modifiers::COMMAND,
+            "alt" | "option" => Modifiers::ALT,
+            "shift" => Modifiers::SHIFT,
+            _ => bail!("unknown modifier \"{part}\" in \"{chord}\", expected Ctrl, Alt or Shift"),
+        });
+    }
+    let key_name = key_name.trim();
+    let Some(key) = Key::from_name(key_name) else {
+        bail!("unknown key \"{key_name}\" in \"{chord}\"");
+    };
+    Ok(KeyboardShortcut::new(modifiers, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use eframe::egui::{Key, KeyboardShortcut, Modifiers};
+    use super::{format_chord, parse_chord, Action, Keymap};
+
+    #[test]
+    pub fn test_chord_round_trip() {
+        let modifiers = [
+            Modifiers::NONE, Modifiers::COMMAND, Modifiers::SHIFT, Modifiers::ALT,
+            Modifiers::COMMAND | Modifiers::SHIFT, Modifiers::COMMAND | Modifiers::ALT | Modifiers::SHIFT,
+        ];
+        for key in Key::ALL {
+            for modifiers in modifiers {
+                let shortcut = KeyboardShortcut::new(modifiers, *key);
+                assert_eq!(parse_chord(&format_chord(&shortcut)).unwrap(), shortcut, "{}", format_chord(&shortcut));
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_parse_chord() {
+        let shortcut = |modifiers, key| KeyboardShortcut::new(modifiers, key);
+        assert_eq!(parse_chord("Ctrl+Shift+S").unwrap(), shortcut(Modifiers::COMMAND | Modifiers::SHIFT, Key::S));
+        assert_eq!(parse_chord(" cmd + alt + Z ").unwrap(), shortcut(Modifiers::COMMAND | Modifiers::ALT, Key::Z));
+        assert_eq!(parse_chord("Ctrl++").unwrap(), shortcut(Modifiers::COMMAND, Key::Plus));
+        assert_eq!(parse_chord("+").unwrap(), shortcut(Modifiers::NONE, Key::Plus));
+        assert_eq!(parse_chord("Ctrl+Plus").unwrap(), shortcut(Modifiers::COMMAND, Key::Plus));
+        assert!(parse_chord("").is_err());
+        assert!(parse_chord("Ctrl+").is_err());
+        assert!(parse_chord("Ctrl++S").is_err());
+        assert!(parse_chord("Hyper+S").is_err());
+        assert!(parse_chord("Ctrl+Banana").is_err());
+    }
+
+    #[test]
+    pub fn test_conflicts() {
+        let mut keymap = Keymap::defaults("keymap.json".into());
+        assert!(keymap.conflicts().is_empty());
+        keymap.set(Action::Redo, keymap.shortcut(Action::Undo));
+        assert_eq!(keymap.conflicts(), [(Action::Undo, Action::Redo)]);
+        assert_eq!(keymap.conflicts_with(Action::Redo), [Action::Undo]);
+        // the first action wins
+        assert_eq!(keymap.action(Key::Z, Modifiers::COMMAND), Some(Action::Undo));
+        keymap.set(Action::Undo, None);
+        assert!(keymap.conflicts().is_empty());
+        assert_eq!(keymap.action(Key::Z, Modifiers::COMMAND), Some(Action::Redo));
+    }
+}
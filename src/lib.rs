@@ -0,0 +1,34 @@
+//! The painting engine, every color is stored as its own raster of presences.
+//! The items re-exported here are the public surface, the modules behind them are free to change
+mod array_queue;
+mod brush;
+mod brush_stroke;
+mod canvas_image;
+pub mod document;
+pub mod export;
+mod gradient;
+mod mask;
+mod operation_log;
+mod packed_u8;
+mod quantize;
+mod raster;
+mod shape;
+mod symmetry;
+pub mod timelapse;
+mod vec_map;
+
+pub use brush::{pixel_brush, round_brush, Brush};
+pub use brush_stroke::{spaced_lerp, BrushStroke};
+pub use canvas_image::{CanvasImage, FillOptions, FillSample};
+pub use gradient::{Gradient, GradientKind};
+pub use operation_log::{Operation, OperationLog};
+pub use packed_u8::PackedU8s;
+pub use quantize::Dithering;
+pub use raster::Raster;
+pub use shape::{Shape, ShapeKind};
+pub use symmetry::{Symmetry, SymmetryKind};
+
+// the dependencies whose types appear in the signatures above, their major versions are pinned
+pub use glam;
+pub use grid;
+pub use image;
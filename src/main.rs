@@ -1,11 +1,7 @@
-mod array_queue;
-mod brush_stroke;
-mod vec_map;
-mod brush;
-mod canvas_image;
-mod raster;
+mod autosave;
+mod clipboard;
+mod keymap;
 mod canvas_app;
-mod packed_u8;
 use canvas_app::CanvasApp;
 use eframe::Result;
 
@@ -0,0 +1,90 @@
+use grid::Grid;
+
+/// Morphological dilation of a mask by a square of side 2*radius+1
+pub fn dilate(mask: &Grid<bool>, radius: usize) -> Grid<bool> {
+    if radius == 0 {
+        return mask.clone();
+    }
+    let (width, height) = mask.size();
+    // the square is separable so we dilate along x then along y
+    let mut horizontal = Grid::new(width, height);
+    for y in 0..height {
+        dilate_line(width, radius, |x| mask[(x, y)], |x| horizontal[(x, y)] = true);
+    }
+    let mut res = Grid::new(width, height);
+    for x in 0..width {
+        dilate_line(height, radius, |y| horizontal[(x, y)], |y| res[(x, y)] = true);
+    }
+    res
+}
+
+fn dilate_line(length: usize, radius: usize, get: impl Fn(usize) -> bool, mut set: impl FnMut(usize)) {
+    // index of the last set value seen, the value at i-radius is set if it is within 2*radius of i
+    let mut last = None;
+    for i in 0..(length+radius) {
+        if i < length && get(i) {
+            last = Some(i);
+        }
+        if i < radius {
+            continue;
+        }
+        if last.is_some_and(|last| i-last <= 2*radius) {
+            set(i-radius);
+        }
+    }
+}
+
+/// Grows a region by `steps` pixels (8-connectivity) into the allowed pixels,
+/// returning the newly reached pixels
+pub fn grow(region: &mut Grid<bool>, allowed: impl Fn((usize, usize)) -> bool, steps: usize) -> Vec<(usize, usize)> {
+    let (width, height) = region.size();
+    let mut frontier = region.indexed_iter()
+        .filter(|(_, &inside)| inside)
+        .map(|(xy, _)| xy)
+        .collect::<Vec<_>>();
+    let mut res = Vec::new();
+    for _ in 0..steps {
+        let mut next = Vec::new();
+        for (x, y) in frontier {
+            for nx in x.saturating_sub(1)..(x+2).min(width) {
+                for ny in y.saturating_sub(1)..(y+2).min(height) {
+                    let xy = (nx, ny);
+                    if region[xy] || !allowed(xy) {
+                        continue;
+                    }
+                    region[xy] = true;
+                    next.push(xy);
+                }
+            }
+        }
+        res.extend_from_slice(&next);
+        frontier = next;
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use grid::Grid;
+    use super::dilate;
+
+    #[test]
+    pub fn test_dilate() {
+        let mut mask = Grid::new(7, 5);
+        mask[(3, 2)] = true;
+        mask[(0, 0)] = true;
+        let dilated = dilate(&mask, 1);
+        let expected = [
+            "##.....",
+            "##ooo..",
+            "..ooo..",
+            "..ooo..",
+            ".......",
+        ];
+        for (y, row) in expected.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                assert_eq!(dilated[(x, y)], c != '.', "at {:?}", (x, y));
+            }
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use std::{fs::File, io::{BufReader, BufWriter, Read, Write}, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+use glam::Vec2;
+use image::Rgba;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    brush::Brush, canvas_image::{CanvasImage, FillOptions}, document,
+    gradient::{Gradient, GradientKind}, shape::Shape
+};
+
+/// Every call that modifies a canvas, colors are stored as unmultiplied rgba
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Operation {
+    /// The content of the canvas when the recording started, as a canvas document
+    Snapshot(Vec<u8>),
+    /// The brush of the following stamps, only recorded when it changes
+    Brush(Brush),
+    Stamps { color: [u8; 4], poses: Vec<Vec2> },
+    Shape { color: [u8; 4], shape: Shape },
+    EraseStamps { opacity: u8, poses: Vec<Vec2> },
+    ClearPreview,
+    ApplyPreview { color: [u8; 4] },
+    ApplyErase { opacity: u8 },
+    Gradient { kind: GradientKind, stops: Vec<(f32, [u8; 4])>, start: Vec2, end: Vec2 },
+    Fill { pos: Vec2, color: [u8; 4], options: FillOptions },
+    Paste { pos: (usize, usize), width: usize, pixels: Vec<u8> },
+    /// A drawing pasted with its presences, as a canvas document
+    PasteCanvas { pos: (usize, usize), document: Vec<u8> },
+    SwapColor { from: [u8; 4], to: [u8; 4] },
+    Clear,
+}
+
+impl Operation {
+    /// Whether the operation changes the color presences, as opposed to only previewing
+    pub fn is_applied(&self) -> bool {
+        !matches!(self, Operation::Brush(_) | Operation::Stamps { .. } | Operation::Shape { .. }
+            | Operation::EraseStamps { .. } | Operation::ClearPreview)
+    }
+}
+
+/// Operations applied since the last checkpoint before the canvas is kept again
+const CHECKPOINT_INTERVAL: usize = 16;
+/// Past this, every other checkpoint is dropped so long recordings keep a bounded memory
+const MAX_CHECKPOINTS: usize = 64;
+
+/// The operations applied to a canvas, replaying them on a blank canvas
+/// of the same size gives back the exact same presences
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OperationLog {
+    pub width: usize,
+    pub height: usize,
+    pub operations: Vec<Operation>,
+    #[serde(skip)]
+    last_brush: Option<Brush>,
+    /// Canvas documents of the state after the operations up to the index, for undo to start from
+    #[serde(skip)]
+    checkpoints: Vec<(usize, Vec<u8>)>,
+    /// The undone operations, the last undone at the end
+    #[serde(skip)]
+    pub(crate) redo: Vec<Vec<Operation>>,
+}
+
+impl OperationLog {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self { width, height, operations: Vec::new(), last_brush: None, checkpoints: Vec::new(), redo: Vec::new() }
+    }
+
+    pub(crate) fn push(&mut self, operation: Operation) {
+        self.operations.push(operation);
+    }
+
+    pub(crate) fn push_brush(&mut self, brush: &Brush) {
+        if self.last_brush.as_ref() != Some(brush) {
+            self.last_brush = Some(brush.clone());
+            self.operations.push(Operation::Brush(brush.clone()));
+        }
+    }
+
+    /// Whether enough operations were applied since the last checkpoint, and none is being previewed
+    pub(crate) fn wants_checkpoint(&self) -> bool {
+        let since = self.checkpoints.last().map_or(0, |(pos, _)| *pos);
+        self.operations.last().is_some_and(Operation::is_applied)
+            && self.operations[since..].iter().filter(|operation| operation.is_applied()).count() >= CHECKPOINT_INTERVAL
+    }
+
+    /// Keeps the canvas document of the state after the current operations
+    pub(crate) fn push_checkpoint(&mut self, document: Vec<u8>) {
+        self.checkpoints.push((self.operations.len(), document));
+        if self.checkpoints.len() > MAX_CHECKPOINTS {
+            let mut i = 0;
+            self.checkpoints.retain(|_| {
+                i += 1;
+                i % 2 == 0
+            });
+        }
+    }
+
+    /// Removes the last operation which changed the presences, along with the previews since then,
+    /// the starting snapshot is kept. The removed operations can be redone until a new one is pushed.
+    /// Returns whether there was an operation to remove
+    pub fn pop_applied(&mut self) -> bool {
+        let last = self.operations.iter()
+            .rposition(|operation| operation.is_applied() && !matches!(operation, Operation::Snapshot(_)));
+        let Some(last) = last else {
+            return false;
+        };
+        // previews after it never got applied
+        self.operations.truncate(last + 1);
+        // the stamps of the removed stroke
+        let first = self.operations[..last].iter().rposition(Operation::is_applied).map_or(0, |i| i + 1);
+        let removed = self.operations.split_off(first);
+        self.redo.push(removed);
+        self.checkpoints.retain(|(pos, _)| *pos <= first);
+        // the next stroke records its brush again
+        self.last_brush = None;
+        true
+    }
+
+    /// The brush of a stroke starting after the operations up to `end`
+    pub(crate) fn brush_at(&self, end: usize) -> Option<Brush> {
+        self.operations[..end].iter().rev().find_map(|operation| match operation {
+            Operation::Brush(brush) => Some(brush.clone()),
+            _ => None,
+        })
+    }
+
+    /// Applies every operation to a blank canvas
+    pub fn replay(&self) -> Result<CanvasImage> {
+        self.replay_with(|_| {})
+    }
+
+    /// Replays the operations, `on_applied` sees the canvas after every operation which changed the presences
+    pub fn replay_with(&self, on_applied: impl FnMut(&CanvasImage)) -> Result<CanvasImage> {
+        self.replay_range(0, CanvasImage::new(self.width, self.height), on_applied)
+    }
+
+    /// Applies the operations since the last checkpoint to it, without the log
+    pub(crate) fn replay_from_checkpoint(&self) -> Result<CanvasImage> {
+        match self.checkpoints.last() {
+            Some((pos, document)) => self.replay_range(*pos, document::read_from(document.as_slice())?, |_| {}),
+            None => self.replay(),
+        }
+    }
+
+    fn replay_range(
+        &self, start: usize, mut image: CanvasImage, mut on_applied: impl FnMut(&CanvasImage),
+    ) -> Result<CanvasImage> {
+        let mut brush = self.brush_at(start);
+        for (i, operation) in self.operations.iter().enumerate().skip(start) {
+            apply_operation(&mut image, operation, &mut brush, i)?;
+            if operation.is_applied() {
+                on_applied(&image);
+            }
+        }
+        Ok(image)
+    }
+
+    pub fn write_to(&self, writer: impl Write) -> Result<()> {
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+
+    pub fn read_from(reader: impl Read) -> Result<Self> {
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut writer = BufWriter::new(
+            File::create(path).with_context(|| format!("couldn't create {}", path.display()))?
+        );
+        self.write_to(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("couldn't open {}", path.display()))?;
+        Self::read_from(BufReader::new(file))
+            .with_context(|| format!("{} is not an operation log", path.display()))
+    }
+}
+
+/// Applies the operation at index `i` of a log, `brush` is the one of the stamps so far
+pub(crate) fn apply_operation(
+    image: &mut CanvasImage, operation: &Operation, brush: &mut Option<Brush>, i: usize,
+) -> Result<()> {
+    let missing_brush = || anyhow!("operation {i} is a stroke without a brush");
+    match operation {
+        Operation::Snapshot(bytes) => *image = document::read_from(bytes.as_slice())?,
+        Operation::Brush(new_brush) => *brush = Some(new_brush.clone()),
+        Operation::Stamps { color, poses } => {
+            image.preview_with(brush.as_ref().ok_or_else(missing_brush)?, Rgba(*color), poses.clone());
+        },
+        Operation::Shape { color, shape } => {
+            image.preview_shape(brush.as_ref().ok_or_else(missing_brush)?, Rgba(*color), shape);
+        },
+        Operation::EraseStamps { opacity, poses } => {
+            image.preview_erase(brush.as_ref().ok_or_else(missing_brush)?, *opacity, poses.clone());
+        },
+        Operation::ClearPreview => {
+            image.clear_preview();
+        },
+        Operation::ApplyPreview { color } => image.apply_preview(Rgba(*color)),
+        Operation::ApplyErase { opacity } => image.apply_erase(*opacity),
+        Operation::Gradient { kind, stops, start, end } => {
+            let gradient = Gradient {
+                kind: *kind,
+                stops: stops.iter().map(|(pos, color)| (*pos, Rgba(*color))).collect(),
+            };
+            image.apply_gradient(&gradient, *start, *end);
+        },
+        Operation::Fill { pos, color, options } => {
+            image.fill(*pos, Rgba(*color), options);
+        },
+        Operation::Paste { pos, width, pixels } => image.add_image(*pos, pixels, *width),
+        Operation::PasteCanvas { pos, document } => {
+            image.add_canvas(*pos, &document::read_from(document.as_slice())?);
+        },
+        Operation::SwapColor { from, to } => {
+            image.swap_color(Rgba(*from), Rgba(*to));
+        },
+        Operation::Clear => image.clear(),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec2;
+    use image::Rgba;
+    use crate::{brush::round_brush, brush_stroke::spaced_lerp, canvas_image::{CanvasImage, FillOptions}};
+    use super::OperationLog;
+
+    const INK: Rgba<u8> = Rgba([20, 30, 200, 255]);
+    const PAINT: Rgba<u8> = Rgba([200, 40, 10, 180]);
+
+    #[test]
+    pub fn test_undo() {
+        let mut image = CanvasImage::new(8, 8);
+        image.add_image((0, 0), &[255; 4*4], 2);
+        image.start_recording();
+        let brush = round_brush(2);
+        image.preview_with(&brush, INK, spaced_lerp(Vec2::new(1., 1.), Vec2::new(6., 6.), brush.spacing));
+        image.apply_preview(INK);
+        let stroke = image.render();
+        image.fill(Vec2::new(7., 0.), PAINT, &FillOptions::default());
+        assert!(image.undo().unwrap());
+        assert_eq!(image.render(), stroke);
+        assert!(image.undo().unwrap());
+        assert!(!image.undo().unwrap());
+        assert_eq!(*image.render().get_pixel(0, 0), Rgba([255; 4]));
+        assert_eq!(image.palette().count(), 1);
+        assert!(image.redo().unwrap());
+        assert_eq!(image.render(), stroke);
+        // a new change drops the undone fill
+        image.fill(Vec2::new(0., 7.), INK, &FillOptions::default());
+        assert!(!image.redo().unwrap());
+    }
+
+    #[test]
+    pub fn test_undo_past_checkpoints() {
+        let mut image = CanvasImage::new(8, 8);
+        image.start_recording();
+        let brush = round_brush(1);
+        let mut renders = vec![image.render()];
+        for i in 0..50 {
+            let pos = Vec2::new((i % 8) as f32, (i / 8) as f32);
+            image.preview_with(&brush, Rgba([i as u8 * 5, 0, 0, 255]), vec![pos]);
+            image.apply_preview(Rgba([i as u8 * 5, 0, 0, 255]));
+            renders.push(image.render());
+        }
+        for _ in 0..20 {
+            assert!(image.undo().unwrap());
+            renders.pop();
+            assert_eq!(&image.render(), renders.last().unwrap());
+        }
+        for _ in 0..5 {
+            assert!(image.redo().unwrap());
+        }
+        assert_eq!(image.render(), image.operation_log().unwrap().replay().unwrap().render());
+        assert_eq!(image.operation_log().unwrap().operations.iter().filter(|op| op.is_applied()).count(), 35);
+    }
+
+    #[test]
+    pub fn test_replay() {
+        let mut image = CanvasImage::new(24, 16);
+        image.add_image((2, 2), &[255; 4*9], 3);
+        image.start_recording();
+        let mut brush = round_brush(4);
+        image.preview_with(&brush, INK, spaced_lerp(Vec2::new(2., 3.), Vec2::new(20., 12.), brush.spacing));
+        image.apply_preview(INK);
+        brush.subpixel = false;
+        image.preview_erase(&brush, 128, spaced_lerp(Vec2::new(4., 12.), Vec2::new(18., 4.), brush.spacing));
+        image.apply_erase(128);
+        image.add_image((0, 14), &[0, 255, 0, 255].repeat(4), 2);
+        image.fill(Vec2::new(20., 2.), PAINT, &FillOptions { tolerance: 10, ..Default::default() });
+
+        let mut bytes = Vec::new();
+        image.operation_log().unwrap().write_to(&mut bytes).unwrap();
+        let replayed = OperationLog::read_from(bytes.as_slice()).unwrap().replay().unwrap();
+        assert_eq!(replayed.render(), image.render());
+        assert!(replayed.palette().map(|(color, raster)| (color, &raster.0))
+            .eq(image.palette().map(|(color, raster)| (color, &raster.0))));
+    }
+}
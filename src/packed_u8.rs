@@ -111,7 +111,7 @@ impl PackedEnum {
             Self::U4(data) => Box::new(data.iter().flat_map(|a| {
                 [(a & mask::<4>()), (a >> 4)]
             })),
-            Self::U8(data) => Box::new(data.iter().map(|a| *a)),
+            Self::U8(data) => Box::new(data.iter().copied()),
         }
     }
 }
@@ -178,7 +178,7 @@ impl PackedU8s {
             }
         } else {
             PackedU8s {
-                data: PackedEnum::U8(values.iter().map(|a| *a as u8).collect()),
+                data: PackedEnum::U8(values.to_vec()),
                 length,
                 mask: mask::<8>()
             }
@@ -194,6 +194,50 @@ impl PackedU8s {
         self.data.get(i)
     }
 
+    /// How many bits each value is stored on
+    pub fn bits(&self) -> u8 {
+        match self.data {
+            PackedEnum::U1(_) => 1,
+            PackedEnum::U2(_) => 2,
+            PackedEnum::U4(_) => 4,
+            PackedEnum::U8(_) => 8,
+        }
+    }
+
+    /// The packed bytes
+    pub fn raw(&self) -> &[u8] {
+        match &self.data {
+            PackedEnum::U1(data) | PackedEnum::U2(data) | PackedEnum::U4(data) | PackedEnum::U8(data) => data,
+        }
+    }
+
+    /// How many bytes `length` values take once packed on `bits`, None if the bits are invalid
+    pub fn raw_len(bits: u8, length: usize) -> Option<usize> {
+        match bits {
+            1 => Some(required_u8::<1>(length)),
+            2 => Some(required_u8::<2>(length)),
+            4 => Some(required_u8::<4>(length)),
+            8 => Some(length),
+            _ => None,
+        }
+    }
+
+    /// Rebuilds packed values from the output of `bits` and `raw`, 
+    /// returns None if the bits are invalid or there's not enough data for length
+    pub fn from_raw(bits: u8, data: Vec<u8>, length: usize) -> Option<Self> {
+        let required = Self::raw_len(bits, length)?;
+        if data.len() < required {
+            return None;
+        }
+        let (data, mask) = match bits {
+            1 => (PackedEnum::U1(data), mask::<1>()),
+            2 => (PackedEnum::U2(data), mask::<2>()),
+            4 => (PackedEnum::U4(data), mask::<4>()),
+            _ => (PackedEnum::U8(data), mask::<8>()),
+        };
+        Some(PackedU8s { data, mask, length })
+    }
+
     #[inline]
     fn upscale_if_needed(&mut self, value: u8) {
         if (value & self.mask) == value {
@@ -245,6 +289,17 @@ mod tests {
         assert!(uints.mask == mask::<BITS>());
     }
 
+    #[test]
+    pub fn test_raw_roundtrip() {
+        let mut rng = rand::thread_rng();
+        let values: [u8; 100] = [(); 100].map(|_| rng.gen_range(0..4));
+        let uints = PackedU8s::from(&values);
+        let uints = PackedU8s::from_raw(uints.bits(), uints.raw().to_vec(), values.len()).unwrap();
+        test_equal(&uints, &values);
+        assert!(PackedU8s::from_raw(3, vec![0; 100], 100).is_none());
+        assert!(PackedU8s::from_raw(2, vec![0; 10], 100).is_none());
+    }
+
     #[test]
     pub fn test_u1() {
         test_ubits::<1>();
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+
+use color_quant::NeuQuant;
+use image::{Rgba, RgbaImage};
+
+/// Maximum number of colors of an indexed image
+pub const MAX_COLORS: usize = 256;
+/// NeuQuant sampling factor, from 1 (best) to 30 (fastest)
+const NEUQUANT_SAMPLING: i32 = 10;
+const BAYER_4X4: [[u8; 4]; 4] = [
+    [0, 8, 2, 10],
+    [12, 4, 14, 6],
+    [3, 11, 1, 9],
+    [15, 7, 13, 5],
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Dithering {
+    /// Every pixel gets the nearest color
+    None,
+    /// A repeating threshold pattern, stable under edits
+    Ordered,
+    /// Diffuses the error of every pixel to its neighbours, smoother but noisier
+    #[default]
+    FloydSteinberg,
+}
+
+impl Dithering {
+    pub const ALL: [Self; 3] = [Self::None, Self::Ordered, Self::FloydSteinberg];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::None => "None",
+            Self::Ordered => "Ordered",
+            Self::FloydSteinberg => "Floyd-Steinberg",
+        }
+    }
+}
+
+/// The colors and the palette index of every pixel, row by row.
+/// Renders with at most `MAX_COLORS` colors are indexed exactly, the others are quantized to the palette
+/// (plus transparency) when it fits, or to colors picked from the render otherwise
+pub fn indexed(render: &RgbaImage, palette: &[Rgba<u8>], dithering: Dithering) -> (Vec<Rgba<u8>>, Vec<u8>) {
+    if let Some(res) = exact_indexed(render) {
+        return res;
+    }
+    let mut colors = palette.to_vec();
+    if render.pixels().any(|pixel| pixel[3] < u8::MAX) && !colors.contains(&Rgba([0; 4])) {
+        colors.push(Rgba([0; 4]));
+    }
+    if colors.is_empty() || colors.len() > MAX_COLORS {
+        colors = NeuQuant::new(NEUQUANT_SAMPLING, MAX_COLORS, render.as_raw())
+            .color_map_rgba()
+            .chunks_exact(4)
+            .map(|c| Rgba([c[0], c[1], c[2], c[3]]))
+            .collect();
+    }
+    let targets = colors.iter().map(|&color| premultiplied(color.0.map(|c| c as f32))).collect::<Vec<_>>();
+    let (width, height) = (render.width() as usize, render.height() as usize);
+    let mut indices = Vec::with_capacity(width*height);
+    // error diffused to the current and next row, with one pixel of margin on both sides
+    let mut errors = vec![[0f32; 4]; (width+2)*2];
+    // spread of the ordered pattern, coarser palettes need a wider one to reach the colors in between
+    let spread = u8::MAX as f32/(colors.len() as f32).cbrt();
+    for y in 0..height {
+        for x in 0..width {
+            let mut rgba = render.get_pixel(x as u32, y as u32).0.map(|c| c as f32);
+            match dithering {
+                Dithering::None => {},
+                Dithering::Ordered => {
+                    let threshold = (BAYER_4X4[y % 4][x % 4] as f32 + 0.5)/16. - 0.5;
+                    // opaque and fully transparent pixels keep their alpha
+                    let channels = if rgba[3] > 0. && rgba[3] < u8::MAX as f32 { 4 } else { 3 };
+                    for c in &mut rgba[..channels] {
+                        *c += threshold*spread;
+                    }
+                },
+                Dithering::FloydSteinberg => {
+                    for (c, error) in rgba.iter_mut().zip(errors[x+1]) {
+                        *c += error;
+                    }
+                },
+            }
+            let rgba = rgba.map(|c| c.clamp(0., u8::MAX as f32));
+            let target = premultiplied(rgba);
+            let (idx, _) = targets.iter()
+                .map(|color| color.iter().zip(target).map(|(a, b)| (a-b).powi(2)).sum::<f32>())
+                .enumerate()
+                .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                .unwrap();
+            indices.push(idx as u8);
+            if dithering == Dithering::FloydSteinberg {
+                let picked = colors[idx].0;
+                for c in 0..4 {
+                    let error = rgba[c] - picked[c] as f32;
+                    errors[x+2][c] += error*7./16.;
+                    errors[width+2 + x][c] += error*3./16.;
+                    errors[width+2 + x+1][c] += error*5./16.;
+                    errors[width+2 + x+2][c] += error*1./16.;
+                }
+            }
+        }
+        // the next row becomes the current one
+        errors.copy_within(width+2.., 0);
+        errors[width+2..].fill([0.; 4]);
+    }
+    (colors, indices)
+}
+
+fn exact_indexed(render: &RgbaImage) -> Option<(Vec<Rgba<u8>>, Vec<u8>)> {
+    let mut colors = Vec::new();
+    let mut color_idx = HashMap::new();
+    let mut indices = Vec::with_capacity(render.len()/4);
+    for pixel in render.pixels() {
+        let idx = *color_idx.entry(*pixel).or_insert_with(|| {
+            colors.push(*pixel);
+            colors.len()-1
+        });
+        if idx >= MAX_COLORS {
+            return None;
+        }
+        indices.push(idx as u8);
+    }
+    Some((colors, indices))
+}
+
+/// Colors are compared premultiplied, so every transparent color is close to transparency
+fn premultiplied(rgba: [f32; 4]) -> [f32; 4] {
+    let alpha = rgba[3]/u8::MAX as f32;
+    [rgba[0]*alpha, rgba[1]*alpha, rgba[2]*alpha, rgba[3]]
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+    use super::{indexed, Dithering};
+
+    const BLACK: Rgba<u8> = Rgba([0, 0, 0, 255]);
+    const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+    /// A ramp from black to white, blue is off by one every other column so there are more than 256 colors
+    fn gray_ramp() -> RgbaImage {
+        RgbaImage::from_fn(300, 8, |x, _| {
+            let value = (x*255/299) as u8;
+            Rgba([value, value, value ^ (x % 2) as u8, 255])
+        })
+    }
+
+    #[test]
+    pub fn test_exact() {
+        let mut render = RgbaImage::new(4, 2);
+        render.put_pixel(1, 0, WHITE);
+        render.put_pixel(3, 1, Rgba([10, 20, 30, 40]));
+        let (colors, indices) = indexed(&render, &[], Dithering::FloydSteinberg);
+        assert_eq!(colors, [Rgba([0; 4]), WHITE, Rgba([10, 20, 30, 40])]);
+        assert_eq!(indices, [0, 1, 0, 0, 0, 0, 0, 2]);
+    }
+
+    #[test]
+    pub fn test_dithering() {
+        let render = gray_ramp();
+        for dithering in Dithering::ALL {
+            let (colors, indices) = indexed(&render, &[BLACK, WHITE], dithering);
+            assert_eq!(colors, [BLACK, WHITE]);
+            let indices = &indices;
+            // the proportion of white pixels of each column follows the ramp
+            let whites = |x0: usize, x1: usize| (0..8)
+                .flat_map(|y| (x0..x1).map(move |x| indices[y*300 + x] as f32))
+                .sum::<f32>()/(8*(x1-x0)) as f32;
+            assert!(whites(0, 30) < 0.15, "{dithering:?}");
+            assert!(whites(270, 300) > 0.85, "{dithering:?}");
+            let middle = whites(120, 180);
+            if dithering == Dithering::None {
+                assert!(middle == 0. || middle == 1. || (0.4..0.6).contains(&middle));
+            } else {
+                assert!((0.4..0.6).contains(&middle), "{dithering:?} {middle}");
+            }
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use glam::IVec2;
+use glam::{IVec2, Vec2};
 use grid::Grid;
 
 #[derive(Clone)]
@@ -19,7 +19,41 @@ impl Raster {
                 continue;
             }
             let xy = (pos.x as usize, pos.y as usize);
-            self.0[xy] = self.0[xy].max(val.clone());
+            self.0[xy] = self.0[xy].max(*val);
         }
     }
+
+    /// The part of the raster in [pos, pos+dims[, the parts outside of the raster are left empty
+    pub fn cropped(&self, pos: [usize; 2], dims: &[usize; 2]) -> Self {
+        let mut res = Raster::new(dims);
+        for ((x, y), val) in res.0.indexed_iter_mut() {
+            if let Some(&src) = self.0.get(pos[0]+x, pos[1]+y) {
+                *val = src;
+            }
+        }
+        res
+    }
+
+    /// Bilinear resampling of the raster to new dimensions, floored so the presences of several colors
+    /// resampled alike never add up to more than they did
+    pub fn resized(&self, dims: &[usize; 2]) -> Self {
+        let (width, height) = self.0.size();
+        let mut res = Raster::new(dims);
+        if width == 0 || height == 0 {
+            return res;
+        }
+        let scale = Vec2::new(width as f32/dims[0] as f32, height as f32/dims[1] as f32);
+        for ((x, y), val) in res.0.indexed_iter_mut() {
+            // sample at the pixel center
+            let src = ((Vec2::new(x as f32, y as f32) + 0.5)*scale - 0.5)
+                .clamp(Vec2::ZERO, Vec2::new((width-1) as f32, (height-1) as f32));
+            let (x0, y0) = (src.x as usize, src.y as usize);
+            let (x1, y1) = ((x0+1).min(width-1), (y0+1).min(height-1));
+            let f = src - Vec2::new(x0 as f32, y0 as f32);
+            let top = self.0[(x0, y0)] as f32*(1.-f.x) + self.0[(x1, y0)] as f32*f.x;
+            let bottom = self.0[(x0, y1)] as f32*(1.-f.x) + self.0[(x1, y1)] as f32*f.x;
+            *val = (top*(1.-f.y) + bottom*f.y).floor() as u8;
+        }
+        res
+    }
 }
@@ -0,0 +1,175 @@
+use std::f32::consts::{FRAC_PI_4, TAU};
+
+use glam::Vec2;
+use serde::{Deserialize, Serialize};
+
+use crate::brush_stroke::spaced_lerp;
+
+/// Number of sub-scanlines sampled per pixel row when computing the coverage of filled shapes
+const SUBSAMPLES: usize = 4;
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ShapeKind {
+    Line,
+    Rectangle,
+    Ellipse,
+    Polygon,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Shape {
+    pub kind: ShapeKind,
+    pub filled: bool,
+    /// 2 corners for lines, rectangles and ellipses, every vertex for polygons
+    pub points: Vec<Vec2>,
+}
+
+impl Shape {
+    pub fn new(kind: ShapeKind, filled: bool, start: Vec2) -> Self {
+        Self { kind, filled, points: vec![start, start] }
+    }
+
+    /// Moves the last point, if `constrain` is set the shape keeps its proportions
+    /// (squares, circles, and lines snapped to 45° for lines and polygons)
+    pub fn move_last(&mut self, pos: Vec2, constrain: bool) {
+        let n = self.points.len();
+        let anchor = self.points[n-2];
+        self.points[n-1] = if constrain { constrained(self.kind, anchor, pos) } else { pos };
+    }
+
+    /// Whether the shape covers an area, as opposed to being stamped along its outline with the brush
+    pub fn is_area(&self) -> bool {
+        self.filled && self.kind != ShapeKind::Line
+    }
+
+    /// The vertices of the shape's outline, the last vertex is connected to the first one if the shape is closed
+    pub fn vertices(&self) -> Vec<Vec2> {
+        let (a, b) = (self.points[0], self.points[self.points.len()-1]);
+        match self.kind {
+            ShapeKind::Line => vec![a, b],
+            ShapeKind::Rectangle => vec![a, Vec2::new(b.x, a.y), b, Vec2::new(a.x, b.y)],
+            ShapeKind::Ellipse => {
+                let center = a.lerp(b, 0.5);
+                let radius = (b-a).abs()/2.;
+                // roughly one vertex every 2 pixels of perimeter
+                let n = ((radius.x+radius.y)*TAU/4.).max(16.) as usize;
+                (0..n).map(|i| {
+                    let angle = i as f32*TAU/n as f32;
+                    center + Vec2::new(angle.cos()*radius.x, angle.sin()*radius.y)
+                }).collect()
+            },
+            ShapeKind::Polygon => self.points.clone(),
+        }
+    }
+
+    /// All the positions the brush needs to be applied to in order to draw the outline
+    pub fn outline(&self, spacing: f32) -> Vec<Vec2> {
+        let mut vertices = self.vertices();
+        if self.kind != ShapeKind::Line {
+            vertices.push(vertices[0]);
+        }
+        let mut res: Vec<Vec2> = vertices.windows(2)
+            .flat_map(|edge| spaced_lerp(edge[1], edge[0], spacing))
+            .collect();
+        res.push(vertices[vertices.len()-1]);
+        res
+    }
+
+    /// Anti-aliased coverage of the shape's area, clipped to dims
+    pub fn coverage(&self, dims: [usize; 2]) -> Vec<((usize, usize), u8)> {
+        polygon_coverage(&self.vertices(), dims)
+    }
+}
+
+fn constrained(kind: ShapeKind, anchor: Vec2, pos: Vec2) -> Vec2 {
+    let diff = pos-anchor;
+    match kind {
+        ShapeKind::Rectangle | ShapeKind::Ellipse => {
+            let side = diff.x.abs().max(diff.y.abs());
+            anchor + Vec2::new(side.copysign(diff.x), side.copysign(diff.y))
+        },
+        ShapeKind::Line | ShapeKind::Polygon => {
+            let angle = (diff.to_angle()/FRAC_PI_4).round()*FRAC_PI_4;
+            anchor + Vec2::from_angle(angle)*diff.length()
+        },
+    }
+}
+
+/// Computes the anti-aliased coverage of a closed polygon using the even-odd rule.
+/// Coverage is exact horizontally and supersampled vertically
+pub fn polygon_coverage(vertices: &[Vec2], dims: [usize; 2]) -> Vec<((usize, usize), u8)> {
+    if vertices.len() < 3 {
+        return Vec::new();
+    }
+    let (min, max) = vertices.iter().fold(
+        (Vec2::MAX, Vec2::MIN),
+        |(min, max), p| (min.min(*p), max.max(*p))
+    );
+    let x0 = min.x.floor().max(0.) as usize;
+    let x1 = (max.x.ceil().max(0.) as usize).min(dims[0]);
+    let y0 = min.y.floor().max(0.) as usize;
+    let y1 = (max.y.ceil().max(0.) as usize).min(dims[1]);
+    let mut res = Vec::new();
+    let mut row = vec![0f32; x1.saturating_sub(x0)];
+    let mut crossings = Vec::new();
+    for y in y0..y1 {
+        row.fill(0.);
+        for s in 0..SUBSAMPLES {
+            let sy = y as f32 + (s as f32 + 0.5)/SUBSAMPLES as f32;
+            crossings.clear();
+            for (i, a) in vertices.iter().enumerate() {
+                let b = vertices[(i+1) % vertices.len()];
+                if (a.y <= sy) != (b.y <= sy) {
+                    crossings.push(a.x + (sy-a.y)/(b.y-a.y)*(b.x-a.x));
+                }
+            }
+            crossings.sort_by(f32::total_cmp);
+            for span in crossings.chunks_exact(2) {
+                let left = span[0].max(x0 as f32);
+                let right = span[1].min(x1 as f32);
+                if left >= right {
+                    continue;
+                }
+                for x in (left.floor() as usize)..(right.ceil() as usize) {
+                    let overlap = right.min(x as f32 + 1.) - left.max(x as f32);
+                    row[x-x0] += overlap/SUBSAMPLES as f32;
+                }
+            }
+        }
+        for (i, &cover) in row.iter().enumerate() {
+            let val = (cover*u8::MAX as f32).round().min(u8::MAX as f32) as u8;
+            if val > 0 {
+                res.push(((x0+i, y), val));
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec2;
+    use super::{Shape, ShapeKind};
+
+    #[test]
+    pub fn test_rectangle_coverage() {
+        let mut shape = Shape::new(ShapeKind::Rectangle, true, Vec2::new(1.5, 1.));
+        shape.move_last(Vec2::new(4., 3.), false);
+        let coverage = shape.coverage([8, 8]);
+        let at = |xy| coverage.iter().find(|(pos, _)| *pos == xy).map(|(_, val)| *val);
+        // the left column is half covered, the rest is fully covered
+        assert_eq!(at((1, 1)), Some(128));
+        assert_eq!(at((2, 2)), Some(u8::MAX));
+        assert_eq!(at((3, 1)), Some(u8::MAX));
+        assert_eq!(at((4, 1)), None);
+        assert_eq!(at((2, 3)), None);
+        assert_eq!(coverage.len(), 6);
+    }
+
+    #[test]
+    pub fn test_constrained_square() {
+        let mut shape = Shape::new(ShapeKind::Rectangle, false, Vec2::new(10., 10.));
+        shape.move_last(Vec2::new(4., 13.), true);
+        assert_eq!(shape.points[1], Vec2::new(4., 16.));
+    }
+}
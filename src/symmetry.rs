@@ -0,0 +1,139 @@
+use std::f32::consts::TAU;
+
+use glam::Vec2;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SymmetryKind {
+    #[default]
+    None,
+    /// Mirrored left to right, across a vertical axis
+    Vertical,
+    /// Mirrored top to bottom, across a horizontal axis
+    Horizontal,
+    /// Mirrored across both axes, 4 copies
+    Both,
+    /// Copies rotated around the center, evenly spaced
+    Radial(u32),
+}
+
+/// Repeats every stamp of a stroke around a center, in canvas pixels
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Symmetry {
+    pub kind: SymmetryKind,
+    pub center: Vec2,
+}
+
+impl Symmetry {
+    pub fn new(kind: SymmetryKind, center: Vec2) -> Self {
+        Self { kind, center }
+    }
+
+    /// Every copy of a stamp position, starting with the position itself. A stamp on an axis
+    /// or on the center is its own copy, it's only listed once so it isn't stamped twice
+    pub fn copies(&self, pos: Vec2) -> Vec<Vec2> {
+        let mirror_x = Vec2::new(2.*self.center.x - pos.x, pos.y);
+        let mirror_y = Vec2::new(pos.x, 2.*self.center.y - pos.y);
+        let copies = match self.kind {
+            SymmetryKind::None => vec![pos],
+            SymmetryKind::Vertical => vec![pos, mirror_x],
+            SymmetryKind::Horizontal => vec![pos, mirror_y],
+            SymmetryKind::Both => vec![pos, mirror_x, mirror_y, 2.*self.center - pos],
+            SymmetryKind::Radial(n) => {
+                let n = n.max(1);
+                (0..n).map(|i| self.center + Vec2::from_angle(TAU*i as f32/n as f32).rotate(pos - self.center))
+                    .collect()
+            },
+        };
+        let mut distinct: Vec<Vec2> = Vec::with_capacity(copies.len());
+        for copy in copies {
+            // the rotations are off by float errors
+            if !distinct.iter().any(|other| other.distance_squared(copy) < 1e-4) {
+                distinct.push(copy);
+            }
+        }
+        distinct
+    }
+
+    /// The stamps of a stroke along with their copies
+    pub fn apply(&self, poses: Vec<Vec2>) -> Vec<Vec2> {
+        if self.kind == SymmetryKind::None {
+            return poses;
+        }
+        poses.into_iter().flat_map(|pos| self.copies(pos)).collect()
+    }
+
+    /// The axes as segments inside a canvas, radial axes start at the center and the first one points up
+    pub fn axes(&self, width: usize, height: usize) -> Vec<(Vec2, Vec2)> {
+        let size = Vec2::new(width as f32, height as f32);
+        let center = self.center.clamp(Vec2::ZERO, size);
+        let vertical = (Vec2::new(center.x, 0.), Vec2::new(center.x, size.y));
+        let horizontal = (Vec2::new(0., center.y), Vec2::new(size.x, center.y));
+        match self.kind {
+            SymmetryKind::None => Vec::new(),
+            SymmetryKind::Vertical => vec![vertical],
+            SymmetryKind::Horizontal => vec![horizontal],
+            SymmetryKind::Both => vec![vertical, horizontal],
+            SymmetryKind::Radial(n) => {
+                let n = n.max(1);
+                (0..n).map(|i| {
+                    let dir = Vec2::from_angle(TAU*i as f32/n as f32).rotate(Vec2::NEG_Y);
+                    // distance to the canvas edge along the ray
+                    let exit = |c: f32, d: f32, max: f32| match d {
+                        d if d > 0. => (max - c)/d,
+                        d if d < 0. => -c/d,
+                        _ => f32::INFINITY,
+                    };
+                    let length = exit(center.x, dir.x, size.x).min(exit(center.y, dir.y, size.y));
+                    (center, center + dir*length)
+                })
+                .collect()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec2;
+    use super::{Symmetry, SymmetryKind};
+
+    fn assert_close(a: &[Vec2], b: &[Vec2]) {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b) {
+            assert!(a.distance(*b) < 1e-4, "{a} != {b}");
+        }
+    }
+
+    #[test]
+    pub fn test_apply() {
+        let center = Vec2::new(10., 5.);
+        let poses = vec![Vec2::new(2., 1.), Vec2::new(3., 1.)];
+        assert_eq!(Symmetry::new(SymmetryKind::None, center).apply(poses.clone()), poses);
+        assert_close(&Symmetry::new(SymmetryKind::Vertical, center).apply(poses.clone()), &[
+            Vec2::new(2., 1.), Vec2::new(18., 1.), Vec2::new(3., 1.), Vec2::new(17., 1.),
+        ]);
+        let both = Symmetry::new(SymmetryKind::Both, center).apply(vec![Vec2::new(2., 1.)]);
+        assert_close(&both, &[Vec2::new(2., 1.), Vec2::new(18., 1.), Vec2::new(2., 9.), Vec2::new(18., 9.)]);
+        let radial = Symmetry::new(SymmetryKind::Radial(4), center).apply(vec![Vec2::new(10., 1.)]);
+        assert_close(&radial, &[Vec2::new(10., 1.), Vec2::new(14., 5.), Vec2::new(10., 9.), Vec2::new(6., 5.)]);
+        // stamps on an axis or the center aren't stamped twice
+        let on_axis = Symmetry::new(SymmetryKind::Both, center).apply(vec![Vec2::new(10., 1.), center]);
+        assert_close(&on_axis, &[Vec2::new(10., 1.), Vec2::new(10., 9.), center]);
+        let radial = Symmetry::new(SymmetryKind::Radial(6), center).apply(vec![center]);
+        assert_close(&radial, &[center]);
+        assert_eq!(Symmetry::new(SymmetryKind::Radial(0), center).apply(vec![Vec2::new(2., 1.)]), [Vec2::new(2., 1.)]);
+    }
+
+    #[test]
+    pub fn test_axes() {
+        let symmetry = Symmetry::new(SymmetryKind::Both, Vec2::new(10., 5.));
+        assert_eq!(symmetry.axes(20, 10), [
+            (Vec2::new(10., 0.), Vec2::new(10., 10.)),
+            (Vec2::new(0., 5.), Vec2::new(20., 5.)),
+        ]);
+        let radial = Symmetry::new(SymmetryKind::Radial(4), Vec2::new(10., 5.)).axes(20, 10);
+        let ends = radial.iter().map(|(_, end)| *end).collect::<Vec<_>>();
+        assert_close(&ends, &[Vec2::new(10., 0.), Vec2::new(20., 5.), Vec2::new(10., 10.), Vec2::new(0., 5.)]);
+        assert_eq!(Symmetry::new(SymmetryKind::Radial(0), Vec2::new(10., 5.)).axes(20, 10).len(), 1);
+    }
+}
@@ -0,0 +1,188 @@
+use std::{fs::File, io::{BufWriter, Write}, path::Path};
+
+use anyhow::{bail, Context, Result};
+use image::{codecs::gif::{GifEncoder, Repeat}, imageops::{self, FilterType}, Delay, Frame, RgbaImage};
+
+use crate::operation_log::OperationLog;
+
+/// Speed of the gif color quantization, from 1 (best colors) to 30 (fastest)
+const GIF_SPEED: i32 = 10;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TimelapseFormat {
+    Gif,
+    Apng,
+}
+
+impl TimelapseFormat {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or_default().to_lowercase();
+        match ext.as_str() {
+            "gif" => Ok(Self::Gif),
+            "png" | "apng" => Ok(Self::Apng),
+            _ => bail!("unsupported timelapse format \"{ext}\", expected gif, png or apng"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TimelapseOptions {
+    /// Number of applied operations (strokes, fills, pastes...) between 2 frames
+    pub interval: usize,
+    pub frame_rate: f32,
+    /// Scale of the frames relative to the canvas, pixels are kept sharp
+    pub scale: f32,
+}
+
+impl Default for TimelapseOptions {
+    fn default() -> Self {
+        Self { interval: 1, frame_rate: 10., scale: 1. }
+    }
+}
+
+/// Size of the frames of a canvas, pixels are kept sharp so they are at least 1x1
+fn frame_size(width: usize, height: usize, scale: f32) -> (u32, u32) {
+    (((width as f32*scale).round() as u32).max(1), ((height as f32*scale).round() as u32).max(1))
+}
+
+/// How many frames `for_each_frame` produces, without replaying the log
+pub fn frame_count(log: &OperationLog, options: &TimelapseOptions) -> usize {
+    let interval = options.interval.max(1);
+    let applied = log.operations.iter().filter(|operation| operation.is_applied()).count();
+    applied/interval + usize::from(applied % interval != 0 || applied < interval)
+}
+
+/// Replays the log, passing the render to `on_frame` every `options.interval` applied operations
+/// as soon as it's captured, the last frame is always the final drawing
+pub fn for_each_frame(
+    log: &OperationLog, options: &TimelapseOptions, mut on_frame: impl FnMut(RgbaImage) -> Result<()>
+) -> Result<()> {
+    if options.scale <= 0. {
+        bail!("the scale must be positive");
+    }
+    let interval = options.interval.max(1);
+    let scaled = |render: RgbaImage| {
+        if options.scale == 1. {
+            return render;
+        }
+        let (width, height) = frame_size(render.width() as usize, render.height() as usize, options.scale);
+        imageops::resize(&render, width, height, FilterType::Nearest)
+    };
+    let mut applied = 0;
+    // the replay can't be interrupted, the first error skips the remaining frames
+    let mut res = Ok(());
+    let image = log.replay_with(|image| {
+        applied += 1;
+        if applied % interval == 0 && res.is_ok() {
+            res = on_frame(scaled(image.render()));
+        }
+    })?;
+    res?;
+    if applied % interval != 0 || applied < interval {
+        on_frame(scaled(image.render()))?;
+    }
+    Ok(())
+}
+
+/// Encodes the timelapse of a log as an endlessly looping animation, the frames are encoded while replaying
+pub fn write_to(log: &OperationLog, options: &TimelapseOptions, format: TimelapseFormat, writer: impl Write) -> Result<()> {
+    if options.frame_rate <= 0. {
+        bail!("the frame rate must be positive");
+    }
+    let delay_ms = (1000./options.frame_rate).round().clamp(1., u16::MAX as f32) as u16;
+    match format {
+        TimelapseFormat::Gif => {
+            let mut encoder = GifEncoder::new_with_speed(writer, GIF_SPEED);
+            encoder.set_repeat(Repeat::Infinite)?;
+            let delay = Delay::from_numer_denom_ms(delay_ms as u32, 1);
+            for_each_frame(log, options, |frame| Ok(encoder.encode_frame(Frame::from_parts(frame, 0, 0, delay))?))?;
+        },
+        TimelapseFormat::Apng => {
+            // apngs start with their frame count and size
+            let (width, height) = frame_size(log.width, log.height, options.scale);
+            let mut encoder = png::Encoder::new(writer, width, height);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_animated(frame_count(log, options) as u32, 0)?;
+            encoder.set_frame_delay(delay_ms, 1000)?;
+            let mut writer = encoder.write_header()?;
+            for_each_frame(log, options, |frame| Ok(writer.write_image_data(frame.as_raw())?))?;
+            writer.finish()?;
+        },
+    }
+    Ok(())
+}
+
+/// Exports the timelapse of a log, in the format matching the extension of the path
+pub fn export(log: &OperationLog, options: &TimelapseOptions, path: &Path) -> Result<()> {
+    let format = TimelapseFormat::from_path(path)?;
+    let mut writer = BufWriter::new(
+        File::create(path).with_context(|| format!("couldn't create {}", path.display()))?
+    );
+    write_to(log, options, format, &mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec2;
+    use image::{codecs::gif::GifDecoder, AnimationDecoder, Rgba, RgbaImage};
+    use crate::{canvas_image::{CanvasImage, FillOptions}, operation_log::OperationLog};
+    use super::{for_each_frame, frame_count, write_to, TimelapseFormat, TimelapseOptions};
+
+    fn log() -> OperationLog {
+        let mut image = CanvasImage::new(8, 6);
+        image.start_recording();
+        for i in 0..5 {
+            image.add_image((i, 0), &[u8::MAX, 0, 0, u8::MAX], 1);
+        }
+        image.fill(Vec2::new(4., 4.), Rgba([0, 0, u8::MAX, u8::MAX]), &FillOptions::default());
+        image.stop_recording().unwrap()
+    }
+
+    fn frames(log: &OperationLog, options: &TimelapseOptions) -> Vec<RgbaImage> {
+        let mut frames = Vec::new();
+        for_each_frame(log, options, |frame| {
+            frames.push(frame);
+            Ok(())
+        }).unwrap();
+        frames
+    }
+
+    #[test]
+    pub fn test_frames() {
+        let options = TimelapseOptions { interval: 4, scale: 2., ..Default::default() };
+        let frames = frames(&log(), &options);
+        // after the 4th paste, then the final fill
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frame_count(&log(), &options), 2);
+        assert_eq!(frame_count(&log(), &TimelapseOptions { interval: 3, ..options.clone() }), 2);
+        assert_eq!(frame_count(&log(), &TimelapseOptions { interval: 10, ..options.clone() }), 1);
+        assert_eq!(frames[0].dimensions(), (16, 12));
+        assert_eq!(*frames[0].get_pixel(7, 0), Rgba([u8::MAX, 0, 0, u8::MAX]));
+        assert_eq!(*frames[0].get_pixel(8, 0), Rgba([0; 4]));
+        assert_eq!(*frames[1].get_pixel(8, 0), Rgba([u8::MAX, 0, 0, u8::MAX]));
+        assert_eq!(*frames[1].get_pixel(15, 11), Rgba([0, 0, u8::MAX, u8::MAX]));
+    }
+
+    #[test]
+    pub fn test_gif() {
+        let options = TimelapseOptions { frame_rate: 5., ..Default::default() };
+        let mut bytes = Vec::new();
+        write_to(&log(), &options, TimelapseFormat::Gif, &mut bytes).unwrap();
+        let decoded = GifDecoder::new(std::io::Cursor::new(bytes)).unwrap().into_frames().collect_frames().unwrap();
+        assert_eq!(decoded.len(), 6);
+        assert_eq!(decoded[0].delay().numer_denom_ms(), (200, 1));
+    }
+
+    #[test]
+    pub fn test_apng() {
+        let options = TimelapseOptions { interval: 2, scale: 0.5, ..Default::default() };
+        let mut bytes = Vec::new();
+        write_to(&log(), &options, TimelapseFormat::Apng, &mut bytes).unwrap();
+        let reader = png::Decoder::new(bytes.as_slice()).read_info().unwrap();
+        assert_eq!(reader.info().animation_control().unwrap().num_frames, 3);
+        assert_eq!(reader.info().size(), (4, 3));
+    }
+}
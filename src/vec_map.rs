@@ -8,14 +8,7 @@ impl<K: Eq, V> VecMap<K, V> {
     }
 
     pub fn position(&self, key: &K) -> Option<usize> {
-        let mut i = 0;
-        for (k, _) in &self.0 {
-            if k == key {
-                return Some(i);
-            }
-            i += 1;
-        }
-        None
+        self.0.iter().position(|(k, _)| k == key)
     }
 }
 